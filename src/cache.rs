@@ -1,6 +1,11 @@
+use serde_derive::Serialize;
 use std::{
-    collections::HashMap,
-    sync::{PoisonError, RwLock},
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        PoisonError, RwLock,
+    },
     time::{Duration, Instant},
 };
 use thiserror::Error;
@@ -23,9 +28,48 @@ pub struct CacheEntry<T: Clone> {
     pub value: T,
 }
 
+/// Counters and current usage of a cache.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries removed to stay within the capacity limits.
+    pub evictions: u64,
+    /// Entries removed by `Cache::purge`.
+    pub expirations: u64,
+}
+
+struct Slot<T: Clone> {
+    entry: CacheEntry<T>,
+    weight: usize,
+    // value of `Cache::clock` at the last access
+    last_used: AtomicU64,
+}
+
+struct Inner<T: Clone> {
+    slots: HashMap<String, Slot<T>>,
+    bytes: usize,
+}
+
 pub struct Cache<T: Clone> {
     timeout: Duration,
-    inner: RwLock<HashMap<String, CacheEntry<T>>>,
+    // entries older than this are removed by `purge`, `None` keeps
+    // expired entries until they have to be evicted to make room
+    retention: Option<Duration>,
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    // approximate size of a value in bytes
+    weigher: fn(&T) -> usize,
+    inner: RwLock<Inner<T>>,
+    // a logical clock is enough to find the least recently used entry,
+    // and unlike Instant it can be updated under a read lock
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
 }
 
 impl<T: Clone> CacheEntry<T> {
@@ -38,13 +82,51 @@ impl<T: Clone> CacheEntry<T> {
 }
 
 impl<T: Clone> Cache<T> {
+    /// An unbounded cache whose entries expire after `timeout`.
     pub fn new(timeout: Duration) -> Self {
         Cache {
             timeout,
-            inner: RwLock::new(HashMap::new()),
+            retention: Some(timeout),
+            max_entries: None,
+            max_bytes: None,
+            weigher: |_| 0,
+            inner: RwLock::new(Inner {
+                slots: HashMap::new(),
+                bytes: 0,
+            }),
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            expirations: AtomicU64::new(0),
         }
     }
 
+    /// Evicts the least recently used entries once there are more than `max_entries`
+    /// entries or their total size as reported by `weigher` exceeds `max_bytes`.
+    pub fn with_limits(
+        mut self,
+        max_entries: Option<usize>,
+        max_bytes: Option<usize>,
+        weigher: fn(&T) -> usize,
+    ) -> Self {
+        self.max_entries = max_entries;
+        self.max_bytes = max_bytes;
+        self.weigher = weigher;
+        self
+    }
+
+    /// Sets how long entries are kept after they were last updated,
+    /// `None` keeps them until they're evicted. Defaults to the timeout.
+    pub fn with_retention(mut self, retention: Option<Duration>) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
     pub fn get(
         &self,
         key: &str,
@@ -52,22 +134,250 @@ impl<T: Clone> Cache<T> {
     ) -> Result<Option<CacheEntry<T>>, CachingError> {
         let inner = self.inner.read()?;
 
-        if let Some(entry) = inner.get(key) {
+        if let Some(slot) = inner.slots.get(key) {
             let now = Instant::now();
-            let age = now - entry.updated;
-            let threshold = expiration.unwrap_or_else(|| self.timeout);
+            let age = now - slot.entry.updated;
+            let threshold = expiration.unwrap_or(self.timeout);
 
             if age <= threshold {
-                return Ok(Some(entry.clone()));
+                slot.last_used.store(self.tick(), Ordering::Relaxed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(slot.entry.clone()));
             }
         }
 
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
         Ok(None)
     }
 
+    /// Returns the entry regardless of its age. Doesn't count as a hit or a miss.
+    pub fn get_stale(&self, key: &str) -> Result<Option<CacheEntry<T>>, CachingError> {
+        let inner = self.inner.read()?;
+        Ok(inner.slots.get(key).map(|slot| slot.entry.clone()))
+    }
+
+    /// Marks the entry as fresh without replacing its value.
+    pub fn touch(&self, key: &str) -> Result<(), CachingError> {
+        let mut inner = self.inner.write()?;
+
+        if let Some(slot) = inner.slots.get_mut(key) {
+            slot.entry.updated = Instant::now();
+            slot.last_used.store(self.tick(), Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
     pub fn set(&self, key: String, value: T) -> Result<(), CachingError> {
+        self.insert(key, CacheEntry::new(value))
+    }
+
+    /// Inserts an entry that was last updated `age` ago. If the monotonic
+    /// clock can't go back that far, the entry is marked as just expired.
+    pub fn set_with_age(&self, key: String, value: T, age: Duration) -> Result<(), CachingError> {
+        let now = Instant::now();
+
+        let updated = now
+            .checked_sub(age)
+            .or_else(|| now.checked_sub(self.timeout.saturating_add(Duration::from_secs(1))))
+            .unwrap_or(now);
+
+        self.insert(key, CacheEntry { updated, value })
+    }
+
+    fn insert(&self, key: String, entry: CacheEntry<T>) -> Result<(), CachingError> {
+        let weight = (self.weigher)(&entry.value);
+        let slot = Slot {
+            entry,
+            weight,
+            last_used: AtomicU64::new(self.tick()),
+        };
+
         let mut inner = self.inner.write()?;
-        inner.insert(key, CacheEntry::new(value));
+
+        inner.bytes += weight;
+
+        if let Some(previous) = inner.slots.insert(key.clone(), slot) {
+            inner.bytes -= previous.weight;
+        }
+
+        self.evict(&mut inner, &key);
+
         Ok(())
     }
+
+    fn is_over_limits(&self, inner: &Inner<T>) -> bool {
+        let too_many =
+            matches!(self.max_entries, Some(max_entries) if inner.slots.len() > max_entries);
+        let too_large = matches!(self.max_bytes, Some(max_bytes) if inner.bytes > max_bytes);
+
+        too_many || too_large
+    }
+
+    // the entry that was just inserted is kept even if it
+    // doesn't fit on its own, otherwise it would never be cached
+    fn evict(&self, inner: &mut Inner<T>, inserted_key: &str) {
+        if !self.is_over_limits(inner) {
+            return;
+        }
+
+        // a single pass over the entries, however many have to be evicted
+        let mut least_recently_used: BinaryHeap<Reverse<(u64, String)>> = inner
+            .slots
+            .iter()
+            .filter(|(key, _)| key.as_str() != inserted_key)
+            .map(|(key, slot)| Reverse((slot.last_used.load(Ordering::Relaxed), key.clone())))
+            .collect();
+
+        while self.is_over_limits(inner) {
+            let key = match least_recently_used.pop() {
+                Some(Reverse((_, key))) => key,
+                None => break,
+            };
+
+            if let Some(slot) = inner.slots.remove(&key) {
+                inner.bytes -= slot.weight;
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), CachingError> {
+        let mut inner = self.inner.write()?;
+
+        if let Some(slot) = inner.slots.remove(key) {
+            inner.bytes -= slot.weight;
+        }
+
+        Ok(())
+    }
+
+    /// Removes entries older than the retention period.
+    pub fn purge(&self) -> Result<(), CachingError> {
+        let retention = match self.retention {
+            Some(retention) => retention,
+            None => return Ok(()),
+        };
+
+        let mut inner = self.inner.write()?;
+        let now = Instant::now();
+
+        let expired: Vec<String> = inner
+            .slots
+            .iter()
+            .filter(|(_, slot)| now - slot.entry.updated > retention)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in expired {
+            if let Some(slot) = inner.slots.remove(&key) {
+                inner.bytes -= slot.weight;
+                self.expirations.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<CacheStats, CachingError> {
+        let inner = self.inner.read()?;
+
+        Ok(CacheStats {
+            entries: inner.slots.len(),
+            bytes: inner.bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_touch_refreshes_expired_entries() {
+        let cache = Cache::new(Duration::from_millis(50));
+        cache.set("key".into(), 1).unwrap();
+
+        thread::sleep(Duration::from_millis(100));
+
+        assert!(cache.get("key", None).unwrap().is_none());
+        assert_eq!(cache.get_stale("key").unwrap().unwrap().value, 1);
+
+        cache.touch("key").unwrap();
+
+        assert_eq!(cache.get("key", None).unwrap().unwrap().value, 1);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used_entries() {
+        let cache = Cache::new(Duration::from_secs(60)).with_limits(Some(2), None, |_| 0);
+
+        cache.set("a".into(), 1).unwrap();
+        cache.set("b".into(), 2).unwrap();
+        cache.get("a", None).unwrap();
+        cache.set("c".into(), 3).unwrap();
+
+        assert!(cache.get_stale("a").unwrap().is_some());
+        assert!(cache.get_stale("b").unwrap().is_none());
+        assert!(cache.get_stale("c").unwrap().is_some());
+        assert_eq!(cache.stats().unwrap().evictions, 1);
+    }
+
+    #[test]
+    fn test_limits_total_size() {
+        let cache =
+            Cache::new(Duration::from_secs(60)).with_limits(None, Some(10), |value: &usize| *value);
+
+        cache.set("a".into(), 4).unwrap();
+        cache.set("b".into(), 4).unwrap();
+        cache.set("c".into(), 4).unwrap();
+
+        assert!(cache.get_stale("a").unwrap().is_none());
+        assert_eq!(cache.stats().unwrap().bytes, 8);
+
+        // too large on its own, but still cached
+        cache.set("d".into(), 20).unwrap();
+
+        assert_eq!(cache.stats().unwrap().entries, 1);
+        assert_eq!(cache.get_stale("d").unwrap().unwrap().value, 20);
+    }
+
+    #[test]
+    fn test_purges_expired_entries() {
+        let cache = Cache::new(Duration::from_millis(50));
+        let unpurged = Cache::new(Duration::from_millis(50)).with_retention(None);
+
+        cache.set("old".into(), 1).unwrap();
+        unpurged.set("old".into(), 1).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        cache.set("new".into(), 2).unwrap();
+
+        cache.purge().unwrap();
+        unpurged.purge().unwrap();
+
+        assert!(cache.get_stale("old").unwrap().is_none());
+        assert!(cache.get_stale("new").unwrap().is_some());
+        assert!(unpurged.get_stale("old").unwrap().is_some());
+        assert_eq!(cache.stats().unwrap().expirations, 1);
+    }
+
+    #[test]
+    fn test_counts_hits_and_misses() {
+        let cache = Cache::new(Duration::from_secs(60));
+
+        cache.set("a".into(), 1).unwrap();
+        cache.get("a", None).unwrap();
+        cache.get("b", None).unwrap();
+        cache.get_stale("a").unwrap();
+
+        let stats = cache.stats().unwrap();
+
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
 }
@@ -4,7 +4,8 @@ use std::{
     collections::HashMap,
     fmt::Display,
     hash::Hash,
-    sync::{mpsc::RecvError, Arc, Mutex, PoisonError},
+    sync::{mpsc::RecvTimeoutError, Arc, Mutex, PoisonError},
+    time::Duration,
 };
 use thiserror::Error;
 
@@ -12,8 +13,10 @@ use thiserror::Error;
 pub enum CoalescingError {
     #[error("poison error")]
     PoisonError,
-    #[error("bus closed")]
-    RecvError(#[from] RecvError),
+    #[error("the task in progress panicked")]
+    LeaderPanicked,
+    #[error("timed out waiting for the task in progress")]
+    Timeout,
 }
 
 impl<T> From<PoisonError<T>> for CoalescingError {
@@ -33,6 +36,33 @@ where
     // the key exists in HashMap and insert it if it doesn't
     // under a single lock, otherwise a race condition might occur
     inflight: Mutex<HashMap<K, Arc<Mutex<Bus<T>>>>>,
+    // how long followers wait for the result, forever if not set
+    timeout: Option<Duration>,
+}
+
+// removes the key once the task is done, even if it panics. that drops
+// the last reference to the bus, and a dropped bus wakes up the followers
+struct InflightGuard<'a, K, T>
+where
+    K: Clone + Display + Eq + Hash,
+    T: Clone + Sync,
+{
+    inflight: &'a Mutex<HashMap<K, Arc<Mutex<Bus<T>>>>>,
+    key: &'a K,
+}
+
+impl<'a, K, T> Drop for InflightGuard<'a, K, T>
+where
+    K: Clone + Display + Eq + Hash,
+    T: Clone + Sync,
+{
+    fn drop(&mut self) {
+        // a poisoned map is still consistent, all it holds are buses
+        self.inflight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(self.key);
+    }
 }
 
 impl<Key, ReturnType> Coalescer<Key, ReturnType>
@@ -43,9 +73,24 @@ where
     pub fn new() -> Self {
         Coalescer {
             inflight: Mutex::new(HashMap::new()),
+            timeout: None,
         }
     }
 
+    /// Limits how long callers wait for a task that is already in progress.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn is_inflight(&self, key: &Key) -> Result<bool, CoalescingError> {
+        Ok(self.inflight.lock()?.contains_key(key))
+    }
+
+    /// Runs `f` unless a task with the same key is in progress, in which case
+    /// its result is returned instead. If that task panics, the callers waiting
+    /// for it get `CoalescingError::LeaderPanicked`, while the panic itself
+    /// unwinds the thread that ran it.
     pub fn execute<F: FnOnce() -> ReturnType>(
         &self,
         key: Key,
@@ -59,7 +104,17 @@ where
 
             debug!("task with key \"{}\" is already in progress", &key);
 
-            return Ok(rx.recv()?.clone());
+            let result = match self.timeout {
+                Some(timeout) => rx.recv_timeout(timeout),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            return match result {
+                Ok(result) => Ok(result),
+                Err(RecvTimeoutError::Timeout) => Err(CoalescingError::Timeout),
+                // the bus is only dropped without a broadcast if the task panicked
+                Err(RecvTimeoutError::Disconnected) => Err(CoalescingError::LeaderPanicked),
+            };
         }
 
         let bus = Arc::new(Mutex::new(Bus::<ReturnType>::new(1)));
@@ -68,13 +123,16 @@ where
 
         debug!("starting a new task with key \"{}\"", &key);
 
+        let guard = InflightGuard {
+            inflight: &self.inflight,
+            key: &key,
+        };
+
         let result = f();
 
-        {
-            // this is in a separate scope because
-            // the guard must be dropped ASAP
-            self.inflight.lock()?.remove(&key);
-        }
+        // the key must be removed before broadcasting, otherwise a caller
+        // could subscribe after the broadcast and never get the result
+        drop(guard);
 
         bus.lock()?.broadcast(result.clone());
 
@@ -87,8 +145,8 @@ mod tests {
     use super::*;
     use rand::{thread_rng, RngCore};
     use std::{
+        panic::{self, AssertUnwindSafe},
         thread::{self, JoinHandle},
-        time::Duration,
     };
 
     #[test]
@@ -107,9 +165,7 @@ mod tests {
             let coalescer = Arc::clone(&coalescer);
 
             let handle = thread::spawn(move || {
-                let result = coalescer.execute("some-key".into(), || {
-                    return do_work();
-                });
+                let result = coalescer.execute("some-key".into(), do_work);
 
                 result.expect("fatal coalescing error")
             });
@@ -129,4 +185,68 @@ mod tests {
             assert_eq!(first, item);
         }
     }
+
+    #[test]
+    fn test_recovers_from_panicking_tasks() {
+        let coalescer = Arc::new(Coalescer::<String, u32>::new());
+
+        let leader = {
+            let coalescer = Arc::clone(&coalescer);
+
+            thread::spawn(move || {
+                coalescer.execute("some-key".into(), || {
+                    thread::sleep(Duration::from_millis(200));
+                    panic!("task failed");
+                })
+            })
+        };
+
+        thread::sleep(Duration::from_millis(50));
+
+        let follower = coalescer.execute("some-key".into(), || 0);
+
+        assert!(matches!(follower, Err(CoalescingError::LeaderPanicked)));
+        assert!(leader.join().is_err());
+        assert!(!coalescer.is_inflight(&"some-key".into()).unwrap());
+
+        // the key is usable again
+        assert_eq!(coalescer.execute("some-key".into(), || 1).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_cleans_up_after_panics_without_followers() {
+        let coalescer = Coalescer::<String, u32>::new();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            coalescer.execute("some-key".into(), || panic!("task failed"))
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(coalescer.execute("some-key".into(), || 1).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_times_out_waiting_for_slow_tasks() {
+        let coalescer =
+            Arc::new(Coalescer::<String, u32>::new().with_timeout(Some(Duration::from_millis(50))));
+
+        let leader = {
+            let coalescer = Arc::clone(&coalescer);
+
+            thread::spawn(move || {
+                coalescer.execute("some-key".into(), || {
+                    thread::sleep(Duration::from_millis(300));
+                    1
+                })
+            })
+        };
+
+        thread::sleep(Duration::from_millis(50));
+
+        let follower = coalescer.execute("some-key".into(), || 0);
+
+        assert!(matches!(follower, Err(CoalescingError::Timeout)));
+        // the slow task itself isn't affected
+        assert_eq!(leader.join().unwrap().unwrap(), 1);
+    }
 }
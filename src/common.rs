@@ -1,15 +1,20 @@
 use crate::{
     config::Config,
+    http::HttpClients,
     npm_registry::{DownloadManager, ManifestRepository},
 };
 use handlebars::Handlebars;
 use regex::Regex;
+use rouille::extension_to_mime;
+use std::{ffi::OsStr, path::Path, sync::Arc};
+use url::Url;
 use urlencoding::encode;
 
 pub struct AppData<'a> {
     pub config: Config,
     pub manifest_repository: ManifestRepository,
     pub download_manager: DownloadManager,
+    pub http_clients: Arc<HttpClients>,
     pub handlebars: Handlebars<'a>,
 }
 
@@ -19,6 +24,40 @@ pub fn filter_string(source: &str) -> String {
     re.replace_all(encode(source).as_ref(), "_").to_string()
 }
 
+/// The /show/ url of a file or directory (with a trailing slash in `path`),
+/// each segment is percent-encoded as they may contain "?", "#" or "%".
+pub fn show_url(name: &str, version: &str, path: &str) -> String {
+    let mut url = Url::parse("http://localhost/show").unwrap();
+
+    url.path_segments_mut()
+        .unwrap()
+        .extend(name.split('/'))
+        .push(version)
+        .extend(path.split('/'));
+
+    url.path().to_string()
+}
+
+pub fn get_mime_type(file_name: &str) -> &'static str {
+    let extension = Path::new(file_name).extension().and_then(OsStr::to_str);
+
+    let mime = extension
+        .map(|extension| {
+            if extension == "md" {
+                return "text/markdown; charset=utf-8";
+            }
+
+            extension_to_mime(extension)
+        })
+        .unwrap_or("text/html");
+
+    if mime == "application/octet-stream" {
+        "text/html"
+    } else {
+        mime
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -30,4 +69,24 @@ mod tests {
             "https_3A_2F_2Fregistry_npmjs_com_2F_40scope_2Fname"
         );
     }
+
+    #[test]
+    fn test_encodes_show_urls() {
+        assert_eq!(
+            show_url("@scope/name", "1.0.0", ""),
+            "/show/@scope/name/1.0.0/"
+        );
+        assert_eq!(
+            show_url("name", "1.0.0", "docs/what? #1 100%.md"),
+            "/show/name/1.0.0/docs/what%3F%20%231%20100%25.md"
+        );
+        assert_eq!(show_url("name", "1.0.0", "lib/"), "/show/name/1.0.0/lib/");
+    }
+
+    #[test]
+    fn test_guesses_mime_types() {
+        assert_eq!(get_mime_type("README.md"), "text/markdown; charset=utf-8");
+        assert_eq!(get_mime_type("cjs/react.js"), "application/javascript");
+        assert_eq!(get_mime_type("LICENSE"), "text/html");
+    }
 }
@@ -1,7 +1,31 @@
-use crate::{common::filter_string, error::NpmPackageServerError};
+use crate::{
+    common::filter_string, error::NpmPackageServerError, http::redact_credentials, npmrc::Npmrc,
+    tarball::ExtractionLimits,
+};
+use rand::{thread_rng, Rng};
+use regex::Regex;
+use semver::{Prerelease, Version};
 use serde_derive::{Deserialize, Serialize};
-use std::{fs::read_to_string, path::Path};
+use std::{
+    env,
+    ffi::OsString,
+    fs::{create_dir_all, read_to_string, remove_file, File},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use toml::from_str;
+use url::Url;
+
+pub const DEFAULT_CONFIG_PATH: &str = "./npm-package-explorer.config.toml";
+
+// environment variables take precedence over the config file,
+// the --config flag takes precedence over CONFIG_PATH_VARIABLE
+const CONFIG_PATH_VARIABLE: &str = "NPM_PACKAGE_EXPLORER_CONFIG";
+const CACHE_DIRECTORY_VARIABLE: &str = "NPM_PACKAGE_EXPLORER_CACHE_DIRECTORY";
+const STATIC_DIRECTORY_VARIABLE: &str = "NPM_PACKAGE_EXPLORER_STATIC_DIRECTORY";
+
+// created and removed to check that the cache directory is writable
+const WRITE_CHECK_FILE_NAME: &str = ".write-check";
 
 fn default_registry() -> String {
     String::from("https://registry.npmjs.org/")
@@ -15,25 +39,304 @@ fn default_index_file() -> String {
     String::from("index.html")
 }
 
+fn default_storage() -> StorageMode {
+    StorageMode::Unpacked
+}
+
+fn default_max_unpacked_size() -> u64 {
+    512 * 1024 * 1024
+}
+
+fn default_max_file_count() -> u64 {
+    50_000
+}
+
+fn default_cache_directory() -> PathBuf {
+    PathBuf::from("./.tmp")
+}
+
+fn default_static_directory() -> PathBuf {
+    PathBuf::from("./static")
+}
+
+fn default_manifest_ttl() -> u64 {
+    15
+}
+
+fn default_badge_max_age() -> u64 {
+    900
+}
+
+fn default_manifest_max_age() -> u64 {
+    300
+}
+
+fn default_manifest_error_ttl() -> u64 {
+    10
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_read_timeout() -> u64 {
+    30
+}
+
+fn default_request_timeout() -> u64 {
+    300
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_base_delay_ms() -> u64 {
+    200
+}
+
+fn default_max_delay_ms() -> u64 {
+    5000
+}
+
+fn default_jitter() -> bool {
+    true
+}
+
+fn default_retry_statuses() -> Vec<u16> {
+    vec![429, 500, 502, 503, 504]
+}
+
+fn default_eviction_interval() -> u64 {
+    300
+}
+
+fn default_file_name_label() -> String {
+    String::from("Name")
+}
+
+fn default_file_size_label() -> String {
+    String::from("Size")
+}
+
+fn default_degraded_label() -> String {
+    String::from("The registry is unavailable, showing the last known versions.")
+}
+
+fn default_not_found_label() -> String {
+    String::from("The package doesn't exist in the registry.")
+}
+
+fn default_unauthorized_label() -> String {
+    String::from("The registry rejected the credentials.")
+}
+
+fn default_forbidden_label() -> String {
+    String::from("The registry denied access to the package.")
+}
+
+fn default_rate_limited_label() -> String {
+    String::from("The registry is rate limiting requests, try again later.")
+}
+
+fn default_unavailable_label() -> String {
+    String::from("The registry is unavailable, try again later.")
+}
+
+fn default_registry_error_label() -> String {
+    String::from("The versions couldn't be fetched from the registry.")
+}
+
+const MANIFEST_CACHE_MAX_BYTES: usize = 256 * 1024 * 1024;
+const INDEX_CACHE_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+fn default_manifest_cache() -> CacheLimits {
+    CacheLimits {
+        max_entries: None,
+        max_bytes: Some(MANIFEST_CACHE_MAX_BYTES),
+    }
+}
+
+fn default_index_cache() -> CacheLimits {
+    CacheLimits {
+        max_entries: None,
+        max_bytes: Some(INDEX_CACHE_MAX_BYTES),
+    }
+}
+
+/// Capacity of an in-memory cache. Limits that aren't set don't apply,
+/// though the config always sets `max_bytes` of its caches.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct CacheLimits {
+    pub max_entries: Option<usize>,
+    /// Approximate total size of the cached values in bytes.
+    pub max_bytes: Option<usize>,
+}
+
+/// Timeouts of requests to the registries, in seconds.
+#[derive(Deserialize, Debug, Clone)]
+pub struct HttpConfig {
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// Applies to waiting for the response and to every read of its body.
+    #[serde(default = "default_read_timeout")]
+    pub read_timeout: u64,
+    /// Limits the whole request including the body. 0 disables this.
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout: default_connect_timeout(),
+            read_timeout: default_read_timeout(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+/// How failed requests to a registry are retried.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RetryConfig {
+    /// Including the first one, 1 disables retries.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every following one.
+    #[serde(default = "default_base_delay_ms")]
+    pub base_delay_ms: u64,
+    /// Upper bound of the delays, including those requested with `Retry-After`.
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Picks delays between half and the full value, so that
+    /// requests failed at the same time aren't retried at the same time.
+    #[serde(default = "default_jitter")]
+    pub jitter: bool,
+    /// Response statuses that are retried. Connection errors always are.
+    #[serde(default = "default_retry_statuses")]
+    pub statuses: Vec<u16>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: default_max_attempts(),
+            base_delay_ms: default_base_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+            jitter: default_jitter(),
+            statuses: default_retry_statuses(),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// How long to wait before retrying after the given attempt (starting at 1).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(32);
+        let delay = self
+            .base_delay_ms
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay_ms);
+
+        if self.jitter {
+            Duration::from_millis(thread_rng().gen_range(delay / 2..=delay))
+        } else {
+            Duration::from_millis(delay)
+        }
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay_ms)
+    }
+}
+
+/// How downloaded package versions are kept on disk.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageMode {
+    /// Every file is unpacked into the cache directory.
+    Unpacked,
+    /// The decompressed tarball is kept as a single file
+    /// with an index of entry offsets.
+    Tarball,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Labels {
     pub title: String,
     pub banner: String,
     pub version: String,
     pub published: String,
+    #[serde(default = "default_file_name_label")]
+    pub file_name: String,
+    #[serde(default = "default_file_size_label")]
+    pub file_size: String,
+    #[serde(default = "default_degraded_label")]
+    pub degraded: String,
+    #[serde(default = "default_not_found_label")]
+    pub not_found: String,
+    #[serde(default = "default_unauthorized_label")]
+    pub unauthorized: String,
+    #[serde(default = "default_forbidden_label")]
+    pub forbidden: String,
+    #[serde(default = "default_rate_limited_label")]
+    pub rate_limited: String,
+    #[serde(default = "default_unavailable_label")]
+    pub unavailable: String,
+    /// Shown for registry errors without a label of their own.
+    #[serde(default = "default_registry_error_label")]
+    pub registry_error: String,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct PackageConfig {
     pub name: String,
     pub alias: Option<String>,
-    #[serde(default = "default_registry")]
+    /// Empty until `prepare`, which falls back to the public registry
+    /// if neither the config nor the `.npmrc` sets one.
+    #[serde(default)]
     pub registry: String,
+    /// Sent as a bearer token.
     pub access_token: Option<String>,
+    /// Reads `access_token` from this environment variable instead.
+    pub access_token_env: Option<String>,
+    /// Reads `access_token` from this file instead, e.g. a container secret.
+    pub access_token_file: Option<PathBuf>,
+    /// Base64 of "username:password" for basic auth, like `_auth` in `.npmrc`.
+    pub auth: Option<String>,
+    /// Basic auth, requires `password`.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sends the credentials with tarball requests to other hosts too.
+    #[serde(default)]
+    pub always_auth: bool,
     #[serde(default = "default_ssl_verify")]
     pub ssl_verify: bool,
+    /// Proxy for requests to the registry, e.g. "http://proxy:3128".
+    /// The proxy environment variables apply if not set.
+    pub proxy: Option<String>,
     #[serde(default = "default_index_file")]
     pub index_file: String,
+    #[serde(default = "default_storage")]
+    pub storage: StorageMode,
+    #[serde(default = "default_max_unpacked_size")]
+    pub max_unpacked_size: u64,
+    #[serde(default = "default_max_file_count")]
+    pub max_file_count: u64,
+    #[serde(default)]
+    pub include_prereleases: bool,
+    pub prerelease_filter: Option<String>,
+    /// Overrides `Config::manifest_ttl` for this package.
+    pub manifest_ttl: Option<u64>,
+    /// Fetch the abbreviated manifest wherever publication times aren't needed.
+    #[serde(default)]
+    pub abbreviated_manifest: bool,
+    /// Maximum size of the downloaded versions of this package in bytes.
+    pub disk_quota: Option<u64>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(skip)]
+    prerelease_regex: Option<Regex>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -44,6 +347,45 @@ pub struct Config {
     pub banner_gradient_right_color: String,
     pub banner_color: String,
     pub labels: Labels,
+    /// Where downloaded package versions are stored.
+    #[serde(default = "default_cache_directory")]
+    pub cache_directory: PathBuf,
+    /// Contains the "templates" and "files" directories.
+    #[serde(default = "default_static_directory")]
+    pub static_directory: PathBuf,
+    /// Seconds a fetched manifest is considered up to date.
+    #[serde(default = "default_manifest_ttl")]
+    pub manifest_ttl: u64,
+    /// Value of `max-age` for badges, in seconds.
+    #[serde(default = "default_badge_max_age")]
+    pub badge_max_age: u64,
+    /// Limits of the in-memory manifest cache.
+    #[serde(default = "default_manifest_cache")]
+    pub manifest_cache: CacheLimits,
+    /// Limits of the in-memory cache of tarball indexes.
+    #[serde(default = "default_index_cache")]
+    pub index_cache: CacheLimits,
+    /// Seconds after its ttl that a manifest is still served for while it's
+    /// refreshed in the background. Older manifests are refreshed before responding.
+    #[serde(default = "default_manifest_max_age")]
+    pub manifest_max_age: u64,
+    /// Seconds a failed manifest fetch is remembered for, during which the
+    /// registry isn't contacted again for that package. 0 disables this.
+    #[serde(default = "default_manifest_error_ttl")]
+    pub manifest_error_ttl: u64,
+    /// Seconds a request waits for the same manifest fetch or download already
+    /// started by another request before giving up. Unlimited if not set.
+    pub coalescing_timeout: Option<u64>,
+    #[serde(default)]
+    pub http: HttpConfig,
+    /// Registries and credentials of packages that don't set them
+    /// are read from this `.npmrc` file.
+    pub npmrc: Option<PathBuf>,
+    /// Maximum size of the cache directory in bytes.
+    pub disk_quota: Option<u64>,
+    /// Seconds between passes removing least recently used versions.
+    #[serde(default = "default_eviction_interval")]
+    pub eviction_interval: u64,
     pub packages: Vec<PackageConfig>,
 }
 
@@ -59,7 +401,8 @@ impl PackageConfig {
     pub fn key(&self) -> String {
         let mut result = String::new();
 
-        result.push_str(&self.registry);
+        // keys are logged and used in file names
+        result.push_str(&redact_credentials(&self.registry));
         result.push_str(&self.name);
 
         result
@@ -70,6 +413,196 @@ impl PackageConfig {
         result.push_str(&self.key());
         filter_string(&result).to_lowercase()
     }
+
+    /// Value of the `Authorization` header, if the package has credentials.
+    pub fn authorization(&self) -> Option<String> {
+        if let Some(access_token) = self.access_token.as_ref() {
+            return Some(format!("Bearer {}", access_token));
+        }
+
+        if let Some(auth) = self.auth.as_ref() {
+            return Some(format!("Basic {}", auth));
+        }
+
+        match (self.username.as_ref(), self.password.as_ref()) {
+            (Some(username), Some(password)) => Some(format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            )),
+            _ => None,
+        }
+    }
+
+    /// Credentials are only sent to the registry host, tarballs hosted
+    /// elsewhere only get them with `always_auth`.
+    pub fn sends_credentials_to(&self, url: &Url) -> bool {
+        if self.always_auth {
+            return true;
+        }
+
+        match Url::parse(&self.registry) {
+            Ok(registry) => {
+                registry.scheme() == url.scheme()
+                    && registry.host_str() == url.host_str()
+                    && registry.port_or_known_default() == url.port_or_known_default()
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn extraction_limits(&self) -> ExtractionLimits {
+        ExtractionLimits {
+            max_unpacked_size: self.max_unpacked_size,
+            max_file_count: self.max_file_count,
+        }
+    }
+
+    /// `None` means the global `Config::manifest_ttl`.
+    pub fn manifest_ttl(&self) -> Option<Duration> {
+        self.manifest_ttl.map(Duration::from_secs)
+    }
+
+    pub fn accepts_version(&self, version: &Version) -> bool {
+        if version.pre == Prerelease::EMPTY {
+            return true;
+        }
+
+        if !self.include_prereleases {
+            return false;
+        }
+
+        match self.prerelease_regex.as_ref() {
+            Some(regex) => regex.is_match(&version.to_string()),
+            None => true,
+        }
+    }
+
+    /// Sets `access_token` from `access_token_env` or `access_token_file`,
+    /// so that it doesn't have to be written into the config file.
+    pub fn resolve_access_token<F: Fn(&str) -> Option<String>>(
+        &mut self,
+        get_variable: F,
+    ) -> Result<(), NpmPackageServerError> {
+        let sources = [
+            self.access_token.is_some(),
+            self.access_token_env.is_some(),
+            self.access_token_file.is_some(),
+        ];
+
+        if sources.iter().filter(|is_set| **is_set).count() > 1 {
+            return Err(NpmPackageServerError::InvalidConfig(format!(
+                "package \"{}\" can't set more than one of the access_token options",
+                self.name
+            )));
+        }
+
+        let token = if let Some(name) = self.access_token_env.as_ref() {
+            get_variable(name).ok_or_else(|| {
+                NpmPackageServerError::InvalidConfig(format!(
+                    "environment variable {} (access_token_env of package \"{}\") isn't set",
+                    name, self.name
+                ))
+            })?
+        } else if let Some(path) = self.access_token_file.as_ref() {
+            read_to_string(path).map_err(|error| {
+                NpmPackageServerError::InvalidConfig(format!(
+                    "failed to read {} (access_token_file of package \"{}\"): {}",
+                    path.display(),
+                    self.name,
+                    error
+                ))
+            })?
+        } else {
+            return Ok(());
+        };
+
+        // secret files usually end with a newline
+        let token = token.trim();
+
+        if token.is_empty() {
+            return Err(NpmPackageServerError::InvalidConfig(format!(
+                "access token of package \"{}\" is empty",
+                self.name
+            )));
+        }
+
+        self.access_token = Some(token.to_string());
+
+        Ok(())
+    }
+
+    /// Takes the registry and the credentials from the `.npmrc`
+    /// unless they are set in the config. Must run before `prepare`.
+    pub fn apply_npmrc(&mut self, npmrc: &Npmrc) -> Result<(), NpmPackageServerError> {
+        if self.registry.is_empty() {
+            self.registry = npmrc.registry_for(&self.name).unwrap_or_default();
+        }
+
+        let has_credentials =
+            self.access_token.is_some() || self.auth.is_some() || self.username.is_some();
+
+        if has_credentials {
+            return Ok(());
+        }
+
+        let registry = if self.registry.is_empty() {
+            default_registry()
+        } else {
+            self.registry.clone()
+        };
+
+        let credentials = npmrc.credentials_for(&registry)?;
+
+        self.access_token = credentials.token;
+        self.auth = credentials.auth;
+        self.username = credentials.username;
+        self.password = credentials.password;
+        self.always_auth = self.always_auth || credentials.always_auth;
+
+        Ok(())
+    }
+
+    pub fn prepare(&mut self) -> Result<(), NpmPackageServerError> {
+        if self.registry.is_empty() {
+            self.registry = default_registry();
+        }
+
+        if self.username.is_some() != self.password.is_some() {
+            return Err(NpmPackageServerError::InvalidConfig(format!(
+                "package \"{}\" needs both username and password for basic auth",
+                self.name
+            )));
+        }
+
+        if let Some(filter) = self.prerelease_filter.as_ref() {
+            let regex = Regex::new(filter).map_err(|error| {
+                NpmPackageServerError::InvalidConfig(format!(
+                    "invalid prerelease_filter for package \"{}\": {}",
+                    self.name, error
+                ))
+            })?;
+
+            self.prerelease_regex = Some(regex);
+        }
+
+        if self.retry.max_attempts == 0 {
+            return Err(NpmPackageServerError::InvalidConfig(format!(
+                "retry.max_attempts of package \"{}\" must be at least 1",
+                self.name
+            )));
+        }
+
+        if let Some(proxy) = self.proxy.as_ref() {
+            reqwest::Proxy::all(proxy).map_err(|error| {
+                NpmPackageServerError::InvalidConfig(format!(
+                    "invalid proxy for package \"{}\": {}",
+                    self.name, error
+                ))
+            })?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Config {
@@ -86,10 +619,444 @@ impl Config {
     }
 
     pub fn get_first_package(&self) -> Option<&PackageConfig> {
-        self.packages.get(0)
+        self.packages.first()
     }
 
+    /// Reads the config file and resolves what it refers to, the `.npmrc`
+    /// and the access tokens in environment variables or files.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, NpmPackageServerError> {
-        Ok(from_str::<Config>(read_to_string(path)?.as_ref())?)
+        let path = path.as_ref();
+
+        let mut config = read_to_string(path)
+            .map_err(NpmPackageServerError::from)
+            .and_then(|source| Config::from_toml(&source))
+            .map_err(|error| {
+                NpmPackageServerError::InvalidConfig(format!(
+                    "failed to read config file {}: {}",
+                    path.display(),
+                    error
+                ))
+            })?;
+
+        config.prepare_packages(|name| env::var(name).ok())?;
+
+        Ok(config)
+    }
+
+    /// Reads the config file given by the command line `args` (without the program name)
+    /// or the environment and applies the environment overrides.
+    pub fn load<I: Iterator<Item = String>>(args: I) -> Result<Config, NpmPackageServerError> {
+        let path = config_path(args, env::var_os(CONFIG_PATH_VARIABLE))?;
+
+        let mut config = Config::from_file(&path)?;
+
+        config.apply_overrides(|name| env::var_os(name));
+        config.validate_directories()?;
+
+        Ok(config)
+    }
+
+    fn apply_overrides<F: Fn(&str) -> Option<OsString>>(&mut self, get_variable: F) {
+        if let Some(cache_directory) = get_variable(CACHE_DIRECTORY_VARIABLE) {
+            self.cache_directory = cache_directory.into();
+        }
+
+        if let Some(static_directory) = get_variable(STATIC_DIRECTORY_VARIABLE) {
+            self.static_directory = static_directory.into();
+        }
+    }
+
+    fn validate_directories(&self) -> Result<(), NpmPackageServerError> {
+        let write_check = self.cache_directory.join(WRITE_CHECK_FILE_NAME);
+
+        create_dir_all(&self.cache_directory)
+            .and_then(|_| File::create(&write_check))
+            .and_then(|_| remove_file(&write_check))
+            .map_err(|error| {
+                NpmPackageServerError::InvalidConfig(format!(
+                    "cache directory {} is not writable: {}",
+                    self.cache_directory.display(),
+                    error
+                ))
+            })?;
+
+        for name in ["templates", "files"].iter() {
+            let directory = self.static_directory.join(name);
+
+            if !directory.is_dir() {
+                return Err(NpmPackageServerError::InvalidConfig(format!(
+                    "static directory {} doesn't exist",
+                    directory.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Only parses the config, its packages aren't usable before `prepare_packages`.
+    pub fn from_toml(source: &str) -> Result<Config, NpmPackageServerError> {
+        let mut config = from_str::<Config>(source)?;
+
+        // a table that only sets max_entries keeps the default byte limit
+        config
+            .manifest_cache
+            .max_bytes
+            .get_or_insert(MANIFEST_CACHE_MAX_BYTES);
+        config
+            .index_cache
+            .max_bytes
+            .get_or_insert(INDEX_CACHE_MAX_BYTES);
+
+        Ok(config)
+    }
+
+    fn prepare_packages<F: Fn(&str) -> Option<String>>(
+        &mut self,
+        get_variable: F,
+    ) -> Result<(), NpmPackageServerError> {
+        let npmrc = match self.npmrc.as_ref() {
+            Some(path) => Some(Npmrc::from_file(path).map_err(|error| {
+                NpmPackageServerError::InvalidConfig(format!(
+                    "failed to read {}: {}",
+                    path.display(),
+                    error
+                ))
+            })?),
+            None => None,
+        };
+
+        for package in self.packages.iter_mut() {
+            package.resolve_access_token(&get_variable)?;
+
+            if let Some(npmrc) = npmrc.as_ref() {
+                package.apply_npmrc(npmrc)?;
+            }
+
+            package.prepare()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn config_path<I: Iterator<Item = String>>(
+    mut args: I,
+    variable: Option<OsString>,
+) -> Result<PathBuf, NpmPackageServerError> {
+    let mut path: Option<PathBuf> = None;
+
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            let value = args.next().ok_or_else(|| {
+                NpmPackageServerError::InvalidConfig(String::from("--config requires a path"))
+            })?;
+
+            path = Some(value.into());
+        } else if let Some(value) = arg.strip_prefix("--config=") {
+            path = Some(value.into());
+        } else {
+            return Err(NpmPackageServerError::InvalidConfig(format!(
+                "unknown argument \"{}\"",
+                arg
+            )));
+        }
+    }
+
+    Ok(path
+        .or_else(|| variable.map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+    use std::fs::{remove_dir_all, write};
+
+    fn args(source: &[&str]) -> impl Iterator<Item = String> {
+        source
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn config_source(packages: &str, tables: &str) -> String {
+        format!(
+            r#"
+            listen_address = "127.0.0.1:8080"
+            banner_gradient_left_color = "red"
+            banner_gradient_right_color = "blue"
+            banner_color = "white"
+            cache_directory = "/var/cache/explorer"
+            packages = {}
+
+            [labels]
+            title = "title"
+            banner = "banner"
+            version = "Version"
+            published = "Published"
+            {}
+            "#,
+            packages, tables
+        )
+    }
+
+    fn create_config_with(tables: &str) -> Config {
+        Config::from_toml(&config_source("[]", tables)).unwrap()
+    }
+
+    // the config and the files it refers to
+    fn create_config_directory(files: &[(&str, &str)]) -> PathBuf {
+        let mut directory = std::env::temp_dir();
+        directory.push(format!(
+            "npm-package-explorer-test-{}",
+            thread_rng().next_u64()
+        ));
+        create_dir_all(&directory).unwrap();
+
+        for (name, contents) in files.iter() {
+            write(directory.join(name), contents).unwrap();
+        }
+
+        directory
+    }
+
+    fn create_config() -> Config {
+        create_config_with("")
+    }
+
+    #[test]
+    fn test_config_path_precedence() {
+        let variable = || Some(OsString::from("/env.toml"));
+
+        assert_eq!(
+            config_path(args(&[]), None).unwrap(),
+            PathBuf::from(DEFAULT_CONFIG_PATH)
+        );
+        assert_eq!(
+            config_path(args(&[]), variable()).unwrap(),
+            PathBuf::from("/env.toml")
+        );
+        assert_eq!(
+            config_path(args(&["--config", "/flag.toml"]), variable()).unwrap(),
+            PathBuf::from("/flag.toml")
+        );
+        assert_eq!(
+            config_path(args(&["--config=/flag.toml"]), None).unwrap(),
+            PathBuf::from("/flag.toml")
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_arguments() {
+        assert!(config_path(args(&["--config"]), None).is_err());
+        assert!(config_path(args(&["--port", "80"]), None).is_err());
+    }
+
+    #[test]
+    fn test_environment_overrides_directories() {
+        let mut config = create_config();
+
+        assert_eq!(config.cache_directory, PathBuf::from("/var/cache/explorer"));
+        assert_eq!(config.static_directory, default_static_directory());
+
+        config.apply_overrides(|name| match name {
+            STATIC_DIRECTORY_VARIABLE => Some(OsString::from("/srv/static")),
+            _ => None,
+        });
+
+        assert_eq!(config.cache_directory, PathBuf::from("/var/cache/explorer"));
+        assert_eq!(config.static_directory, PathBuf::from("/srv/static"));
+    }
+
+    #[test]
+    fn test_keeps_default_cache_byte_limits() {
+        let config = create_config_with("[manifest_cache]\nmax_entries = 100");
+
+        assert_eq!(config.manifest_cache.max_entries, Some(100));
+        assert_eq!(
+            config.manifest_cache.max_bytes,
+            Some(MANIFEST_CACHE_MAX_BYTES)
+        );
+        assert_eq!(config.index_cache.max_bytes, Some(INDEX_CACHE_MAX_BYTES));
+
+        let config = create_config_with("[index_cache]\nmax_bytes = 1024");
+
+        assert_eq!(config.index_cache.max_bytes, Some(1024));
+    }
+
+    #[test]
+    fn test_backs_off_exponentially() {
+        let retry = RetryConfig {
+            jitter: false,
+            ..RetryConfig::default()
+        };
+
+        let delays: Vec<u64> = [1, 2, 3, 10]
+            .iter()
+            .map(|attempt| retry.delay(*attempt).as_millis() as u64)
+            .collect();
+
+        assert_eq!(delays, vec![200, 400, 800, 5000]);
+
+        let jittered = RetryConfig::default();
+
+        for attempt in 1..6 {
+            let delay = jittered.delay(attempt);
+
+            assert!(delay >= retry.delay(attempt) / 2);
+            assert!(delay <= retry.delay(attempt));
+        }
+    }
+
+    #[test]
+    fn test_takes_registry_and_credentials_from_npmrc() {
+        let npmrc = Npmrc::parse(
+            "@internal:registry=https://npm.example.com/\n\
+             //npm.example.com/:username=ci\n\
+             //npm.example.com/:_password=aHVudGVyMg==\n\
+             always-auth=true\n",
+            |_| None,
+        )
+        .unwrap();
+
+        let mut package = from_str::<PackageConfig>(r#"name = "@internal/ui""#).unwrap();
+        package.apply_npmrc(&npmrc).unwrap();
+        package.prepare().unwrap();
+
+        assert_eq!(package.registry, "https://npm.example.com/");
+        assert_eq!(
+            package.authorization().unwrap(),
+            format!("Basic {}", base64::encode("ci:hunter2"))
+        );
+        assert!(package.always_auth);
+        assert!(package.sends_credentials_to(&Url::parse("https://cdn.example.com/x").unwrap()));
+
+        // the config takes precedence
+        let mut package = from_str::<PackageConfig>(
+            r#"
+            name = "@internal/ui"
+            registry = "https://other.example.com/"
+            access_token = "token"
+            "#,
+        )
+        .unwrap();
+        package.apply_npmrc(&npmrc).unwrap();
+        package.prepare().unwrap();
+
+        assert_eq!(package.registry, "https://other.example.com/");
+        assert_eq!(package.authorization().unwrap(), "Bearer token");
+        assert!(!package.always_auth);
+    }
+
+    #[test]
+    fn test_sends_credentials_to_the_registry_host() {
+        let mut package = from_str::<PackageConfig>(
+            r#"
+            name = "react"
+            registry = "https://npm.example.com/repository/npm/"
+            access_token = "token"
+            "#,
+        )
+        .unwrap();
+        package.prepare().unwrap();
+
+        let sends_to = |package: &PackageConfig, url: &str| {
+            package.sends_credentials_to(&Url::parse(url).unwrap())
+        };
+
+        assert!(sends_to(
+            &package,
+            "https://npm.example.com/repository/npm/react/-/react-17.0.2.tgz"
+        ));
+        assert!(sends_to(
+            &package,
+            "https://npm.example.com:443/other/react.tgz"
+        ));
+        assert!(!sends_to(&package, "https://cdn.example.com/react.tgz"));
+        assert!(!sends_to(
+            &package,
+            "https://npm.example.com:8443/react.tgz"
+        ));
+        assert!(!sends_to(&package, "http://npm.example.com/react.tgz"));
+
+        package.always_auth = true;
+
+        assert!(sends_to(&package, "https://cdn.example.com/react.tgz"));
+    }
+
+    #[test]
+    fn test_reads_access_tokens_when_loading() {
+        let directory = create_config_directory(&[("npm-token", "from-file\n")]);
+        let packages = format!(
+            "[{{ name = \"react\", access_token_file = \"{}\" }}]",
+            directory.join("npm-token").display()
+        );
+        let path = directory.join("config.toml");
+        write(&path, config_source(&packages, "")).unwrap();
+
+        let config = Config::from_file(&path).unwrap();
+
+        assert_eq!(
+            config.packages[0].authorization().unwrap(),
+            "Bearer from-file"
+        );
+        assert_eq!(config.packages[0].registry, default_registry());
+
+        // parsing alone leaves them to `from_file`
+        let config = Config::from_toml(&config_source(&packages, "")).unwrap();
+
+        assert_eq!(config.packages[0].access_token, None);
+
+        let packages =
+            "[{ name = \"react\", access_token_env = \"NPM_PACKAGE_EXPLORER_UNSET_TOKEN\" }]";
+        write(&path, config_source(packages, "")).unwrap();
+
+        let error = Config::from_file(&path).unwrap_err().to_string();
+
+        assert!(
+            error.contains("NPM_PACKAGE_EXPLORER_UNSET_TOKEN"),
+            "{}",
+            error
+        );
+        assert!(!error.contains("failed to read config file"), "{}", error);
+
+        remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_resolves_access_tokens() {
+        let variables = |name: &str| match name {
+            "NPM_TOKEN" => Some(String::from("from-env\n")),
+            _ => None,
+        };
+
+        let mut package =
+            from_str::<PackageConfig>("name = \"react\"\naccess_token_env = \"NPM_TOKEN\"")
+                .unwrap();
+        package.resolve_access_token(variables).unwrap();
+
+        assert_eq!(package.access_token.unwrap(), "from-env");
+
+        let mut package =
+            from_str::<PackageConfig>("name = \"react\"\naccess_token_env = \"MISSING\"").unwrap();
+
+        assert!(package.resolve_access_token(variables).is_err());
+
+        let mut package = from_str::<PackageConfig>(
+            "name = \"react\"\naccess_token_file = \"/nonexistent/npm-token\"",
+        )
+        .unwrap();
+
+        assert!(package.resolve_access_token(variables).is_err());
+
+        let mut package = from_str::<PackageConfig>(
+            "name = \"react\"\naccess_token = \"a\"\naccess_token_env = \"NPM_TOKEN\"",
+        )
+        .unwrap();
+
+        assert!(package.resolve_access_token(variables).is_err());
     }
 }
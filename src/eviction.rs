@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    fs::{read_dir, symlink_metadata},
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// A fully downloaded package version in the cache directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedVersion {
+    /// Name of the package directory, i.e. `PackageConfig::identifier_safe_key`.
+    pub package_key: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub last_access: SystemTime,
+}
+
+/// Total size of all files in a directory, links are not followed.
+pub fn directory_size<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    let mut size = 0;
+
+    for entry in read_dir(path)? {
+        let entry = entry?;
+        let metadata = symlink_metadata(entry.path())?;
+
+        if metadata.is_dir() {
+            size += directory_size(entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// Picks the least recently used versions to remove so that every package
+/// fits into its quota in `package_quotas` and the whole cache fits into
+/// `global_quota`. Versions for which `is_pinned` returns true are kept.
+pub fn select_for_eviction<F>(
+    mut versions: Vec<CachedVersion>,
+    global_quota: Option<u64>,
+    package_quotas: &HashMap<String, u64>,
+    is_pinned: F,
+) -> Vec<CachedVersion>
+where
+    F: Fn(&CachedVersion) -> bool,
+{
+    versions.sort_by_key(|version| version.last_access);
+
+    let mut package_sizes: HashMap<&str, u64> = HashMap::new();
+
+    for version in versions.iter() {
+        *package_sizes.entry(&version.package_key).or_insert(0) += version.size;
+    }
+
+    let mut total_size: u64 = versions.iter().map(|version| version.size).sum();
+    let mut evicted = vec![false; versions.len()];
+
+    for (index, version) in versions.iter().enumerate() {
+        let quota = match package_quotas.get(&version.package_key) {
+            Some(quota) => *quota,
+            None => continue,
+        };
+
+        let package_size = package_sizes.get_mut(version.package_key.as_str()).unwrap();
+
+        if *package_size > quota && !is_pinned(version) {
+            *package_size -= version.size;
+            total_size -= version.size;
+            evicted[index] = true;
+        }
+    }
+
+    if let Some(global_quota) = global_quota {
+        for (index, version) in versions.iter().enumerate() {
+            if total_size <= global_quota {
+                break;
+            }
+
+            if !evicted[index] && !is_pinned(version) {
+                total_size -= version.size;
+                evicted[index] = true;
+            }
+        }
+    }
+
+    versions
+        .into_iter()
+        .zip(evicted)
+        .filter_map(|(version, evicted)| if evicted { Some(version) } else { None })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn version(package_key: &str, name: &str, size: u64, last_access: u64) -> CachedVersion {
+        CachedVersion {
+            package_key: package_key.into(),
+            path: PathBuf::from(package_key).join(name),
+            size,
+            last_access: SystemTime::UNIX_EPOCH + Duration::from_secs(last_access),
+        }
+    }
+
+    fn names(versions: Vec<CachedVersion>) -> Vec<String> {
+        versions
+            .into_iter()
+            .map(|version| version.path.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used_first() {
+        let versions = vec![
+            version("a", "3", 10, 30),
+            version("a", "1", 10, 10),
+            version("b", "1", 10, 20),
+        ];
+
+        let evicted = select_for_eviction(versions, Some(15), &HashMap::new(), |_| false);
+
+        assert_eq!(names(evicted), vec!["a/1", "b/1"]);
+    }
+
+    #[test]
+    fn test_applies_package_quotas() {
+        let versions = vec![
+            version("a", "1", 10, 10),
+            version("a", "2", 10, 20),
+            version("a", "3", 10, 30),
+            version("b", "1", 10, 5),
+        ];
+
+        let mut package_quotas = HashMap::new();
+        package_quotas.insert(String::from("a"), 15);
+
+        let evicted = select_for_eviction(versions, None, &package_quotas, |_| false);
+
+        assert_eq!(names(evicted), vec!["a/1", "a/2"]);
+    }
+
+    #[test]
+    fn test_keeps_pinned_versions() {
+        let versions = vec![version("a", "1", 10, 10), version("a", "2", 10, 20)];
+
+        let evicted = select_for_eviction(versions, Some(0), &HashMap::new(), |version| {
+            version.path.ends_with("1")
+        });
+
+        assert_eq!(names(evicted), vec!["a/2"]);
+    }
+
+    #[test]
+    fn test_keeps_everything_within_quota() {
+        let versions = vec![version("a", "1", 10, 10), version("b", "1", 10, 20)];
+
+        let evicted = select_for_eviction(versions, Some(20), &HashMap::new(), |_| false);
+
+        assert!(evicted.is_empty());
+    }
+}
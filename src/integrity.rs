@@ -0,0 +1,181 @@
+use sha1::Sha1;
+use sha2::{Digest, Sha512};
+use std::{
+    fmt,
+    io::{self, Read},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha1,
+    Sha512,
+}
+
+/// The digest a tarball is expected to have, taken either from
+/// `dist.integrity` (SRI, e.g. "sha512-<base64>") or `dist.shasum` (hex sha1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedDigest {
+    pub algorithm: Algorithm,
+    pub digest: Vec<u8>,
+}
+
+enum Hasher {
+    Sha1(Sha1),
+    Sha512(Sha512),
+}
+
+/// Hashes everything read through it.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+fn decode_hex(source: &str) -> Option<Vec<u8>> {
+    source
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            if pair.len() != 2 {
+                return None;
+            }
+
+            u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()
+        })
+        .collect()
+}
+
+fn parse_sri(integrity: &str) -> Option<ExpectedDigest> {
+    // SRI strings may list several hashes, prefer the strongest supported one
+    let mut result: Option<ExpectedDigest> = None;
+
+    for item in integrity.split_whitespace() {
+        let (algorithm, digest) = match item.split_once('-') {
+            Some(("sha512", digest)) => (Algorithm::Sha512, digest),
+            Some(("sha1", digest)) => (Algorithm::Sha1, digest),
+            _ => continue,
+        };
+
+        // options like "sha512-<base64>?foo" are allowed by the SRI spec
+        let digest = digest.split('?').next().unwrap_or_default();
+
+        let is_stronger = match result.as_ref() {
+            Some(current) => current.algorithm == Algorithm::Sha1 && algorithm == Algorithm::Sha512,
+            None => true,
+        };
+
+        if let (true, Ok(digest)) = (is_stronger, base64::decode(digest)) {
+            result = Some(ExpectedDigest { algorithm, digest });
+        }
+    }
+
+    result
+}
+
+impl ExpectedDigest {
+    pub fn from_dist(integrity: Option<&str>, shasum: Option<&str>) -> Option<Self> {
+        integrity.and_then(parse_sri).or_else(|| {
+            Some(ExpectedDigest {
+                algorithm: Algorithm::Sha1,
+                digest: decode_hex(shasum?.trim())?,
+            })
+        })
+    }
+}
+
+impl fmt::Display for ExpectedDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.algorithm {
+            Algorithm::Sha1 => write!(f, "sha1-{}", base64::encode(&self.digest)),
+            Algorithm::Sha512 => write!(f, "sha512-{}", base64::encode(&self.digest)),
+        }
+    }
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, algorithm: Algorithm) -> Self {
+        let hasher = match algorithm {
+            Algorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        };
+
+        HashingReader { inner, hasher }
+    }
+
+    pub fn finalize(self) -> ExpectedDigest {
+        match self.hasher {
+            Hasher::Sha1(hasher) => ExpectedDigest {
+                algorithm: Algorithm::Sha1,
+                digest: hasher.finalize().to_vec(),
+            },
+            Hasher::Sha512(hasher) => ExpectedDigest {
+                algorithm: Algorithm::Sha512,
+                digest: hasher.finalize().to_vec(),
+            },
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        match &mut self.hasher {
+            Hasher::Sha1(hasher) => hasher.update(&buf[..read]),
+            Hasher::Sha512(hasher) => hasher.update(&buf[..read]),
+        }
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA512: &str =
+        "sha512-m3HSJL1i83hdltRq0+o9czGb+8KJDKra4t/3JRlnPKcjI8PZm6XBHXx6zG4UuMXaDEZjR1wuXDre9G9zvN7AQw==";
+    const HELLO_SHA1: &str = "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d";
+
+    fn hash(data: &[u8], algorithm: Algorithm) -> ExpectedDigest {
+        let mut reader = HashingReader::new(data, algorithm);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        reader.finalize()
+    }
+
+    #[test]
+    fn test_prefers_integrity_over_shasum() {
+        let expected = ExpectedDigest::from_dist(Some(HELLO_SHA512), Some(HELLO_SHA1)).unwrap();
+
+        assert_eq!(expected.algorithm, Algorithm::Sha512);
+        assert_eq!(expected, hash(b"hello", Algorithm::Sha512));
+        assert_eq!(expected.to_string(), HELLO_SHA512);
+    }
+
+    #[test]
+    fn test_falls_back_to_shasum() {
+        let expected = ExpectedDigest::from_dist(None, Some(HELLO_SHA1)).unwrap();
+
+        assert_eq!(expected, hash(b"hello", Algorithm::Sha1));
+        assert_ne!(expected, hash(b"hello!", Algorithm::Sha1));
+    }
+
+    #[test]
+    fn test_picks_strongest_sri_hash() {
+        let integrity = format!(
+            "sha1-qvTGHdzF6KLavt4PO0gs2a6pQ00= {} sha256-abc",
+            HELLO_SHA512
+        );
+        let expected = ExpectedDigest::from_dist(Some(&integrity), None).unwrap();
+
+        assert_eq!(expected.algorithm, Algorithm::Sha512);
+    }
+
+    #[test]
+    fn test_ignores_missing_or_malformed_digests() {
+        assert_eq!(ExpectedDigest::from_dist(None, None), None);
+        assert_eq!(
+            ExpectedDigest::from_dist(Some("md5-abc"), Some("xyz")),
+            None
+        );
+    }
+}
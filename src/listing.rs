@@ -0,0 +1,249 @@
+use crate::common::get_mime_type;
+use crate::error::NpmPackageServerError;
+use crate::storage::{EntryKind, PackageFiles};
+use serde_derive::Serialize;
+use std::{fs::read_dir, io, path::Path};
+use urlencoding::encode;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub name: String,
+    /// The name percent-encoded for links.
+    pub href: String,
+    pub is_directory: bool,
+    pub size: u64,
+    pub formatted_size: String,
+}
+
+/// Package entry metadata in the format of unpkg's `?meta` responses.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EntryMeta {
+    File {
+        path: String,
+        size: u64,
+        #[serde(rename = "contentType")]
+        content_type: String,
+    },
+    Directory {
+        path: String,
+        files: Vec<EntryMeta>,
+    },
+}
+
+impl DirectoryEntry {
+    pub fn new(name: String, is_directory: bool, size: u64) -> Self {
+        DirectoryEntry {
+            href: encode(&name).into_owned(),
+            name,
+            is_directory,
+            size,
+            formatted_size: format_size(size),
+        }
+    }
+}
+
+/// Sorts directories first, then by name.
+pub fn sort_entries(entries: &mut [DirectoryEntry]) {
+    entries.sort_by(|a, b| {
+        b.is_directory
+            .cmp(&a.is_directory)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+}
+
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["kB", "MB", "GB", "TB"];
+
+    if size < 1000 {
+        return format!("{} B", size);
+    }
+
+    let mut value = size as f64 / 1000.0;
+    let mut unit = 0;
+
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Lists a single directory, directories first, then by name.
+pub fn read_directory<P: AsRef<Path>>(path: P) -> io::Result<Vec<DirectoryEntry>> {
+    let mut entries = vec![];
+
+    for entry in read_dir(path)? {
+        let entry = entry?;
+        // doesn't follow symlinks
+        let mut metadata = entry.metadata()?;
+
+        if metadata.file_type().is_symlink() {
+            // like in tarball mode, links to files are listed as the files
+            // they point to, links to directories and dangling links are skipped
+            match entry.path().metadata() {
+                Ok(target) if target.is_file() => metadata = target,
+                _ => continue,
+            }
+        }
+
+        let size = if metadata.is_dir() { 0 } else { metadata.len() };
+
+        entries.push(DirectoryEntry::new(
+            entry.file_name().to_string_lossy().into_owned(),
+            metadata.is_dir(),
+            size,
+        ));
+    }
+
+    sort_entries(&mut entries);
+
+    Ok(entries)
+}
+
+/// Recursively collects metadata of a package entry,
+/// `path` is relative to the package root, e.g. `cjs/`.
+pub fn read_meta(files: &dyn PackageFiles, path: &str) -> Result<EntryMeta, NpmPackageServerError> {
+    let path = path.trim_end_matches('/');
+    let public_path = format!("/{}", path);
+
+    let kind = files
+        .entry_kind(path)?
+        .ok_or_else(|| NpmPackageServerError::NoSuchFile(path.to_string()))?;
+
+    if let EntryKind::File { size } = kind {
+        return Ok(EntryMeta::File {
+            content_type: get_mime_type(&public_path).to_string(),
+            path: public_path,
+            size,
+        });
+    }
+
+    let mut result = vec![];
+
+    for entry in files.read_directory(path)? {
+        let child_path = if path.is_empty() {
+            entry.name
+        } else {
+            format!("{}/{}", path, entry.name)
+        };
+
+        // links to directories aren't listed, so this can't recurse forever
+        if entry.is_directory {
+            result.push(read_meta(files, &child_path)?);
+        } else {
+            result.push(EntryMeta::File {
+                content_type: get_mime_type(&child_path).to_string(),
+                path: format!("/{}", child_path),
+                size: entry.size,
+            });
+        }
+    }
+
+    Ok(EntryMeta::Directory {
+        path: public_path,
+        files: result,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::UnpackedPackage;
+    use rand::{thread_rng, RngCore};
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn test_formats_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(1000), "1.0 kB");
+        assert_eq!(format_size(1536), "1.5 kB");
+        assert_eq!(format_size(2_500_000), "2.5 MB");
+    }
+
+    #[test]
+    fn test_encodes_links_to_entries() {
+        let entry = DirectoryEntry::new(String::from("what? #1 100%.md"), false, 0);
+
+        assert_eq!(entry.name, "what? #1 100%.md");
+        assert_eq!(entry.href, "what%3F%20%231%20100%25.md");
+    }
+
+    #[test]
+    fn test_serializes_meta_like_unpkg() {
+        let meta = EntryMeta::Directory {
+            path: "/".into(),
+            files: vec![EntryMeta::File {
+                path: "/README.md".into(),
+                size: 12,
+                content_type: "text/markdown; charset=utf-8".into(),
+            }],
+        };
+
+        assert_eq!(
+            serde_json::to_value(&meta).unwrap(),
+            json!({
+                "type": "directory",
+                "path": "/",
+                "files": [{
+                    "type": "file",
+                    "path": "/README.md",
+                    "size": 12,
+                    "contentType": "text/markdown; charset=utf-8"
+                }]
+            })
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_skips_symlinked_directories() {
+        use std::os::unix::fs::symlink;
+
+        let mut directory = std::env::temp_dir();
+        directory.push(format!(
+            "npm-package-explorer-test-{}",
+            thread_rng().next_u64()
+        ));
+
+        create_dir_all(directory.join("lib")).unwrap();
+        write(directory.join("lib/index.js"), "1;").unwrap();
+        symlink(".", directory.join("a")).unwrap();
+        symlink(".", directory.join("lib/b")).unwrap();
+        symlink("lib/index.js", directory.join("main.js")).unwrap();
+        symlink("missing.js", directory.join("dangling.js")).unwrap();
+
+        let files = UnpackedPackage::new(directory.clone());
+        let meta = read_meta(&files, "").unwrap();
+        let file = |path: &str| EntryMeta::File {
+            path: path.into(),
+            size: 2,
+            content_type: get_mime_type(path).to_string(),
+        };
+
+        assert_eq!(
+            meta,
+            EntryMeta::Directory {
+                path: "/".into(),
+                files: vec![
+                    EntryMeta::Directory {
+                        path: "/lib".into(),
+                        files: vec![file("/lib/index.js")],
+                    },
+                    file("/main.js"),
+                ],
+            }
+        );
+
+        assert_eq!(files.entry_kind("a").unwrap(), None);
+        assert_eq!(files.entry_kind("a/lib/index.js").unwrap(), None);
+        assert_eq!(
+            files.entry_kind("main.js").unwrap(),
+            Some(EntryKind::File { size: 2 })
+        );
+
+        remove_dir_all(directory).unwrap();
+    }
+}
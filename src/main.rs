@@ -1,44 +1,90 @@
 use crate::common::AppData;
 use crate::config::Config;
+use crate::http::HttpClients;
 use crate::npm_registry::ManifestRepository;
-use crate::routes::{badge_handler, index_handler, list_versions_handler};
+use crate::routes::{
+    badge_handler, error_response, index_handler, list_versions_handler, metrics_handler,
+};
 use error::NpmPackageServerError;
 use handlebars::Handlebars;
+use log::{error, warn};
 use npm_registry::DownloadManager;
 use rouille::{match_assets, router, start_server, Request, Response};
 use routes::show_handler;
 use std::io;
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::{env, process};
 
 mod cache;
 mod coalescer;
 mod common;
 mod config;
 mod error;
+mod eviction;
+mod http;
+mod integrity;
+mod listing;
 mod npm_registry;
+mod npmrc;
 mod request;
 mod routes;
+mod storage;
+mod tarball;
+#[cfg(test)]
+mod test_server;
 
 #[macro_use]
 extern crate serde_json;
 
-fn result_to_response(result: Result<Response, NpmPackageServerError>) -> Response {
+const CACHE_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+// "/api/*" always responds with JSON, other routes
+// only if the client doesn't accept HTML
+fn wants_json(request: &Request) -> bool {
+    if request.url().starts_with("/api/") {
+        return true;
+    }
+
+    let accept = request.header("Accept").unwrap_or_default();
+
+    accept.contains("application/json") && !accept.contains("text/html")
+}
+
+fn result_to_response(
+    request: &Request,
+    app_data: &AppData,
+    result: Result<Response, NpmPackageServerError>,
+) -> Response {
     match result {
         Ok(response) => response,
         Err(error) => {
-            let message = error.to_string();
-
-            match error {
-                _ => Response::text(message).with_status_code(500),
+            if error.status_code() >= 500 {
+                warn!("{} {}: {}", request.method(), request.raw_url(), error);
             }
+
+            error_response(app_data, &error, wants_json(request))
         }
     }
 }
 
+// `Request::get_param` only matches "name=value" pairs,
+// so valueless flags like "?meta" have to be checked manually
+fn has_flag(request: &Request, name: &str) -> bool {
+    request
+        .raw_query_string()
+        .split('&')
+        .any(|pair| pair.split('=').next() == Some(name))
+}
+
 fn handler(request: &Request, app_data: Arc<AppData>) -> Response {
     {
         if let Some(nested_request) = request.remove_prefix("/static") {
-            let assets_response = match_assets(&nested_request, "./static/files");
+            let assets_response = match_assets(
+                &nested_request,
+                &app_data.config.static_directory.join("files"),
+            );
 
             if assets_response.is_success() {
                 return assets_response;
@@ -50,7 +96,7 @@ fn handler(request: &Request, app_data: Arc<AppData>) -> Response {
         (GET) (/) => {
             let package_name = request.get_param("package");
 
-            result_to_response(index_handler(
+            result_to_response(request, &app_data, index_handler(
                 Arc::clone(&app_data),
                 package_name
             ))
@@ -58,15 +104,18 @@ fn handler(request: &Request, app_data: Arc<AppData>) -> Response {
         (GET) (/api/versions) => {
             let jsonp = request.get_param("jsonp");
 
-            result_to_response(list_versions_handler(
+            result_to_response(request, &app_data, list_versions_handler(
                 Arc::clone(&app_data),
                 jsonp
             ))
         },
+        (GET) (/api/metrics) => {
+            result_to_response(request, &app_data, metrics_handler(Arc::clone(&app_data)))
+        },
         (GET) (/badge) => {
             let package_name = request.get_param("package");
 
-            result_to_response(badge_handler(
+            result_to_response(request, &app_data, badge_handler(
                 Arc::clone(&app_data),
                 package_name
             ))
@@ -74,10 +123,12 @@ fn handler(request: &Request, app_data: Arc<AppData>) -> Response {
         _ => {
             if let Some(nested_show_request) = request.remove_prefix("/show/") {
                 let url = nested_show_request.url();
+                let meta = has_flag(request, "meta");
 
-                return result_to_response(show_handler(
+                return result_to_response(request, &app_data, show_handler(
                     Arc::clone(&app_data),
-                    url
+                    url,
+                    meta
                 ));
             }
 
@@ -89,20 +140,81 @@ fn handler(request: &Request, app_data: Arc<AppData>) -> Response {
 fn main() -> std::io::Result<()> {
     env_logger::builder().format_timestamp_millis().init();
 
+    let config = match Config::load(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(error) => {
+            error!("{}", error);
+            process::exit(1);
+        }
+    };
+
+    let coalescing_timeout = config.coalescing_timeout.map(Duration::from_secs);
+    let http_clients = Arc::new(HttpClients::new(config.http.clone()));
+
     let app_data = Arc::new(AppData {
-        config: Config::from_file("./npm-package-explorer.config.toml")
-            .expect("config file ./npm-package-explorer.config.toml doesn't exist"),
-        manifest_repository: ManifestRepository::new(),
-        download_manager: DownloadManager::new(),
+        manifest_repository: ManifestRepository::new(
+            &config.cache_directory,
+            Arc::clone(&http_clients),
+            Duration::from_secs(config.manifest_ttl),
+            Duration::from_secs(config.manifest_max_age),
+            Duration::from_secs(config.manifest_error_ttl),
+            &config.manifest_cache,
+            coalescing_timeout,
+        ),
+        download_manager: DownloadManager::new(
+            config.cache_directory.clone(),
+            Arc::clone(&http_clients),
+            &config.index_cache,
+            coalescing_timeout,
+        ),
+        http_clients,
         handlebars: {
             let mut handlebars = Handlebars::new();
             handlebars
-                .register_templates_directory(".html", "./static/templates")
+                .register_templates_directory(".html", config.static_directory.join("templates"))
                 .unwrap();
             handlebars
         },
+        config,
     });
 
+    app_data
+        .manifest_repository
+        .restore(&app_data.config.packages);
+
+    if let Err(error) = app_data.download_manager.remove_incomplete_downloads() {
+        warn!("failed to remove incomplete downloads: {}", error);
+    }
+
+    {
+        let app_data = Arc::clone(&app_data);
+
+        thread::spawn(move || loop {
+            if let Err(error) = app_data.download_manager.evict(&app_data.config) {
+                warn!("failed to evict cached packages: {}", error);
+            }
+
+            thread::sleep(Duration::from_secs(app_data.config.eviction_interval));
+        });
+    }
+
+    {
+        let app_data = Arc::clone(&app_data);
+
+        thread::spawn(move || loop {
+            thread::sleep(CACHE_PURGE_INTERVAL);
+
+            let result = app_data
+                .manifest_repository
+                .purge()
+                .and_then(|_| app_data.download_manager.purge());
+
+            if let Err(error) = result {
+                warn!("failed to purge expired cache entries: {}", error);
+            }
+        });
+    }
+
     let listen_address = app_data.config.listen_address.clone();
 
     start_server(listen_address, move |request| {
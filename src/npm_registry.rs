@@ -1,54 +1,104 @@
-use crate::cache::Cache;
+use crate::cache::{Cache, CacheStats, CachingError};
 use crate::coalescer::Coalescer;
-use crate::config::PackageConfig;
+use crate::common::filter_string;
+use crate::config::{CacheLimits, Config, PackageConfig, StorageMode};
 use crate::error::ManifestFetchError;
 use crate::error::NpmPackageServerError;
 use crate::error::TarballDownloadError;
+use crate::eviction::{directory_size, select_for_eviction, CachedVersion};
+use crate::http::{body_snippet, redact_credentials, DeadlineReader, HttpClients};
+use crate::integrity::{Algorithm, ExpectedDigest, HashingReader};
 use crate::request::PackageFileRequest;
+use crate::storage::{PackageFiles, TarballPackage, UnpackedPackage};
+use crate::tarball::{copy_archive, unpack_safely, TarballIndex};
 use chrono::{DateTime, Utc};
 use flate2::bufread::GzDecoder;
-use log::debug;
-use reqwest::blocking::Client;
+use log::{debug, info, warn};
+use rand::{thread_rng, RngCore};
+use reqwest::blocking::Response;
 use reqwest::header;
+use reqwest::StatusCode;
 use reqwest::Url;
-use semver::{Prerelease, Version};
-use serde::Deserialize;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
 use serde_json::from_str;
-use std::collections::HashMap;
-use std::fs::create_dir_all;
-use std::io::BufReader;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, DirEntry, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::Duration;
-use tar::Archive;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use urlencoding::encode;
 
-#[derive(Debug, Clone, Deserialize)]
+// written into a version directory once its tarball is fully unpacked
+const COMPLETION_MARKER: &str = ".complete";
+
+// npm tarballs keep the package contents in this directory
+const PACKAGE_DIRECTORY: &str = "package";
+
+// used by StorageMode::Tarball instead of PACKAGE_DIRECTORY,
+// such versions are stored in "<version>.tar" directories so
+// that aliases of a package with different modes don't clash
+const TARBALL_DIRECTORY_SUFFIX: &str = ".tar";
+const TARBALL_FILE_NAME: &str = "package.tar";
+const INDEX_FILE_NAME: &str = "index.json";
+
+// the abbreviated ("corgi") manifest format, falls back to the full one
+// on registries that don't support it. it doesn't contain `time`
+const ABBREVIATED_MANIFEST_ACCEPT: &str =
+    "application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*";
+
+// manifests are persisted to "<cache directory>/<MANIFEST_DIRECTORY>/<key>.json"
+const MANIFEST_DIRECTORY: &str = "manifests";
+
+// tarballs are unpacked into "<version><PARTIAL_SUFFIX><random>"
+// and renamed to "<version>" once complete
+const PARTIAL_SUFFIX: &str = ".partial-";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TarballUrl(String);
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct NpmDistInfo {
     tarball: TarballUrl,
+    integrity: Option<String>,
+    shasum: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct NpmVersionInfo {
     dist: NpmDistInfo,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct NpmPackageManifest {
     versions: HashMap<String, NpmVersionInfo>,
+    #[serde(default)]
     time: HashMap<String, String>,
+    #[serde(rename = "dist-tags", default)]
+    dist_tags: HashMap<String, String>,
 }
 
 #[derive(Clone)]
 pub struct VersionManifest {
     version_str: String, // could this be removed?
     pub version: Version,
-    pub published: DateTime<Utc>,
+    /// Unknown if the manifest was fetched in the abbreviated format.
+    pub published: Option<DateTime<Utc>>,
     pub tarball_url: TarballUrl,
+    pub expected_digest: Option<ExpectedDigest>,
+}
+
+/// `ETag` and `Last-Modified` of a fetched manifest,
+/// sent back to the registry to avoid downloading it again.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+struct ManifestValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
 }
 
 #[derive(Clone)]
@@ -56,30 +106,155 @@ pub struct PackageManifest {
     pub versions: Vec<Arc<VersionManifest>>,
     pub registry_url: String,
     lookup: HashMap<String, Arc<VersionManifest>>,
+    dist_tags: HashMap<String, String>,
+    validators: ManifestValidators,
+}
+
+/// Which manifest format a caller needs. `Abbreviated` is only
+/// used for packages with `abbreviated_manifest` enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestDetail {
+    /// Versions, dist-tags and tarballs.
+    Abbreviated,
+    /// Everything in `Abbreviated` plus publication times.
+    Full,
+}
+
+/// Whether a manifest reflects the latest registry state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestStatus {
+    Fresh,
+    /// The last fetch failed, this is the last successfully fetched copy.
+    Degraded,
 }
 
 pub struct ManifestRepository {
+    store: Arc<ManifestStore>,
+    // used for packages without their own `manifest_ttl`
+    ttl: Duration,
+    // for this long after their ttl, manifests are served while being
+    // refreshed in the background, older ones are fetched synchronously
+    max_age: Duration,
+}
+
+/// A manifest as written to the cache directory.
+#[derive(Deserialize, Serialize)]
+struct PersistedManifest {
+    /// Seconds since the Unix epoch.
+    fetched_at: u64,
+    validators: ManifestValidators,
+    manifest: NpmPackageManifest,
+}
+
+// shared with background refreshes
+struct ManifestStore {
+    // where manifests are persisted to
+    directory: PathBuf,
+    http_clients: Arc<HttpClients>,
     cache: Cache<Arc<PackageManifest>>,
     coalescer: Coalescer<String, Result<Arc<PackageManifest>, NpmPackageServerError>>,
+    // keys with a background refresh in progress
+    refreshing: Mutex<HashSet<String>>,
+    // keys whose last fetch failed
+    failing: Mutex<HashSet<String>>,
+    // recent fetch errors, returned instead of contacting the registry again
+    errors: Cache<ManifestFetchError>,
+    // errors aren't remembered if zero
+    error_ttl: Duration,
+}
+
+// removes the key from `ManifestStore::refreshing` even if the refresh panics
+struct RefreshGuard {
+    store: Arc<ManifestStore>,
+    key: String,
 }
 
 pub struct DownloadPaths {
+    pub storage: StorageMode,
     pub root_directory: PathBuf,
     pub package_directory: PathBuf,
-    pub requested_file_path: PathBuf,
+    pub tarball_path: PathBuf,
+    pub index_path: PathBuf,
 }
 
 pub struct DownloadManager {
+    cache_directory: PathBuf,
+    http_clients: Arc<HttpClients>,
     coalescer: Coalescer<String, Result<(), TarballDownloadError>>,
+    indexes: Cache<Arc<TarballIndex>>,
+    // number of requests currently served from a version directory,
+    // such directories are never evicted
+    leases: Mutex<HashMap<PathBuf, usize>>,
+    // falls back to the modification time of the completion
+    // marker for versions that weren't accessed since startup
+    access_times: Mutex<HashMap<PathBuf, SystemTime>>,
+    // complete versions never change, so their sizes are only computed once
+    sizes: Mutex<HashMap<PathBuf, u64>>,
+}
+
+/// Protects a version directory from eviction while it's alive.
+pub struct VersionLease<'a> {
+    manager: &'a DownloadManager,
+    root_directory: PathBuf,
+}
+
+// the guarded maps stay consistent even if a thread
+// panicked while holding the lock, so poisoning is ignored
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn status_error(package_config: &PackageConfig, response: Response) -> ManifestFetchError {
+    let registry = redact_credentials(&package_config.registry);
+    let status = response.status();
+
+    if status == StatusCode::NOT_FOUND {
+        return ManifestFetchError::PackageDoesNotExistError {
+            registry,
+            name: package_config.name.clone(),
+        };
+    }
+
+    let body = body_snippet(response);
+    let status = status.as_u16();
+
+    match status {
+        401 => ManifestFetchError::Unauthorized {
+            registry,
+            status,
+            body,
+        },
+        403 => ManifestFetchError::Forbidden {
+            registry,
+            name: package_config.name.clone(),
+            status,
+            body,
+        },
+        429 => ManifestFetchError::RateLimited {
+            registry,
+            status,
+            body,
+        },
+        500..=599 => ManifestFetchError::Unavailable {
+            registry,
+            status,
+            body,
+        },
+        _ => ManifestFetchError::UnexpectedStatus {
+            registry,
+            status,
+            body,
+        },
+    }
 }
 
+/// Returns `None` if the registry responded with 304 Not Modified.
 fn fetch_manifest(
+    clients: &HttpClients,
     package_config: &PackageConfig,
-) -> Result<NpmPackageManifest, ManifestFetchError> {
-    let client = Client::builder()
-        .danger_accept_invalid_certs(!package_config.ssl_verify)
-        .build()?;
-
+    detail: ManifestDetail,
+    validators: Option<&ManifestValidators>,
+) -> Result<Option<(NpmPackageManifest, ManifestValidators)>, ManifestFetchError> {
     let full_package_url = format!(
         "{}{}",
         package_config.registry,
@@ -87,88 +262,347 @@ fn fetch_manifest(
     );
 
     let url = Url::parse(&full_package_url)?;
-    let mut builder = client.get(url.clone());
+    let mut builder = clients.get(package_config, url.clone())?;
+
+    if detail == ManifestDetail::Abbreviated {
+        builder = builder.header(header::ACCEPT, ABBREVIATED_MANIFEST_ACCEPT);
+    }
 
-    match package_config.access_token.as_ref() {
-        Some(access_token) => {
-            debug!("adding authorization header");
-            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", access_token));
+    if let Some(validators) = validators {
+        if let Some(etag) = validators.etag.as_ref() {
+            builder = builder.header(header::IF_NONE_MATCH, etag);
         }
-        None => {}
-    };
 
-    debug!("downloading {}", url);
-    let response = builder.send()?;
+        if let Some(last_modified) = validators.last_modified.as_ref() {
+            builder = builder.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    debug!("downloading {}", redact_credentials(url.as_str()));
+    let deadline = clients.deadline();
+    let response = clients.send(package_config, builder, deadline)?;
     debug!("content-length: {:?}", response.content_length());
 
-    if response.status() != 200 {
-        return Err(ManifestFetchError::PackageDoesNotExistError {
-            registry: package_config.registry.clone(),
-            name: package_config.name.clone(),
-        });
+    if validators.is_some() && response.status() == StatusCode::NOT_MODIFIED {
+        debug!("{} is not modified", redact_credentials(url.as_str()));
+        return Ok(None);
+    }
+
+    if response.status() != StatusCode::OK {
+        return Err(status_error(package_config, response));
     }
 
-    let response_text = response.text()?;
+    let header_value = |name: header::HeaderName| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+
+    let validators = ManifestValidators {
+        etag: header_value(header::ETAG),
+        last_modified: header_value(header::LAST_MODIFIED),
+    };
+
+    let mut response_text = String::new();
+    DeadlineReader::new(response, deadline).read_to_string(&mut response_text)?;
     debug!("parsing {} bytes of text", response_text.len());
     let deserialized: NpmPackageManifest = from_str(&response_text)?;
     debug!("done!");
 
-    Ok(deserialized)
+    Ok(Some((deserialized, validators)))
+}
+
+/// Downloads a tarball and passes the decompressed tar stream to `consume`,
+/// verifying the integrity of the compressed stream afterwards.
+fn download_tarball<F>(
+    clients: &HttpClients,
+    tarball_url: &TarballUrl,
+    expected_digest: Option<&ExpectedDigest>,
+    package_config: &PackageConfig,
+    consume: F,
+) -> Result<(), TarballDownloadError>
+where
+    F: FnOnce(&mut dyn Read) -> Result<(), TarballDownloadError>,
+{
+    let parsed_url = Url::parse(&tarball_url.to_string())?;
+    let builder = clients.get(package_config, parsed_url.clone())?;
+
+    debug!("downloading {}", redact_credentials(parsed_url.as_str()));
+    let deadline = clients.deadline();
+    let response = clients.send(package_config, builder, deadline)?;
+    debug!("content-length: {:?}", response.content_length());
+
+    if response.status() != StatusCode::OK {
+        return Err(TarballDownloadError::UnexpectedStatus {
+            url: redact_credentials(parsed_url.as_str()),
+            status: response.status().as_u16(),
+            body: body_snippet(response),
+        });
+    }
+
+    // the compressed stream is hashed while it is being consumed
+    let algorithm = expected_digest.map_or(Algorithm::Sha512, |digest| digest.algorithm);
+    let buf_reader = BufReader::new(HashingReader::new(
+        DeadlineReader::new(response, deadline),
+        algorithm,
+    ));
+    let mut deflater = GzDecoder::new(buf_reader);
+
+    consume(&mut deflater)?;
+
+    // the tar archive ends before the gzip stream does
+    let mut buf_reader = deflater.into_inner();
+    io::copy(&mut buf_reader, &mut io::sink())?;
+    let actual_digest = buf_reader.into_inner().finalize();
+
+    match expected_digest {
+        Some(expected_digest) if *expected_digest != actual_digest => {
+            return Err(TarballDownloadError::IntegrityMismatch {
+                expected: expected_digest.to_string(),
+                actual: actual_digest.to_string(),
+            });
+        }
+        Some(_) => debug!("integrity verified: {}", actual_digest),
+        None => warn!(
+            "no integrity information for {}",
+            redact_credentials(parsed_url.as_str())
+        ),
+    }
+
+    debug!("done!");
+
+    Ok(())
 }
 
 pub fn download_and_unpack_tarball<P: AsRef<Path>>(
+    clients: &HttpClients,
     tarball_url: &TarballUrl,
+    expected_digest: Option<&ExpectedDigest>,
     destination_dir: P,
     package_config: &PackageConfig,
 ) -> Result<(), TarballDownloadError> {
     create_dir_all(&destination_dir)?;
 
-    let client = Client::builder()
-        .danger_accept_invalid_certs(!package_config.ssl_verify)
-        .build()?;
+    download_tarball(
+        clients,
+        tarball_url,
+        expected_digest,
+        package_config,
+        |reader| {
+            unpack_safely(
+                reader,
+                &destination_dir,
+                &package_config.extraction_limits(),
+            )
+        },
+    )
+}
 
-    let parsed_url = Url::parse(&tarball_url.to_string())?;
-    let mut builder = client.get(parsed_url.clone());
+/// Stores the decompressed tarball in `destination_dir`
+/// along with an index of its entries.
+pub fn download_and_index_tarball<P: AsRef<Path>>(
+    clients: &HttpClients,
+    tarball_url: &TarballUrl,
+    expected_digest: Option<&ExpectedDigest>,
+    destination_dir: P,
+    package_config: &PackageConfig,
+) -> Result<(), TarballDownloadError> {
+    create_dir_all(&destination_dir)?;
 
-    match package_config.access_token.as_ref() {
-        Some(access_token) => {
-            debug!("adding authorization header");
-            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", access_token));
-        }
-        None => {}
+    let limits = package_config.extraction_limits();
+    let tarball_path = destination_dir.as_ref().join(TARBALL_FILE_NAME);
+
+    download_tarball(
+        clients,
+        tarball_url,
+        expected_digest,
+        package_config,
+        |reader| copy_archive(reader, &mut File::create(&tarball_path)?, &limits),
+    )?;
+
+    let index = TarballIndex::build(BufReader::new(File::open(&tarball_path)?), &limits)?;
+    let index_file = File::create(destination_dir.as_ref().join(INDEX_FILE_NAME))?;
+    serde_json::to_writer(index_file, &index).map_err(io::Error::from)?;
+
+    Ok(())
+}
+
+/// Unpacks the tarball into a temporary sibling of the root directory
+/// and moves it into place only after the extraction has succeeded,
+/// so an interrupted download never leaves a half-populated directory.
+fn download_and_unpack_tarball_atomically(
+    clients: &HttpClients,
+    version: &VersionManifest,
+    download_paths: &DownloadPaths,
+    package_config: &PackageConfig,
+) -> Result<(), TarballDownloadError> {
+    if download_paths.is_unpacked() {
+        // another request has finished the download in the meantime
+        return Ok(());
+    }
+
+    let root_directory = download_paths.root_directory.as_path();
+
+    let mut partial_directory_name = root_directory
+        .file_name()
+        .unwrap_or_default()
+        .to_os_string();
+    partial_directory_name.push(format!("{}{:08x}", PARTIAL_SUFFIX, thread_rng().next_u32()));
+    let partial_directory = root_directory.with_file_name(partial_directory_name);
+
+    let download = match download_paths.storage {
+        StorageMode::Unpacked => download_and_unpack_tarball,
+        StorageMode::Tarball => download_and_index_tarball,
     };
 
-    debug!("downloading / unpacking {}", parsed_url);
-    let response = builder.send()?;
-    debug!("content-length: {:?}", response.content_length());
+    let result = download(
+        clients,
+        &version.tarball_url,
+        version.expected_digest.as_ref(),
+        &partial_directory,
+        package_config,
+    )
+    .and_then(|_| {
+        File::create(partial_directory.join(COMPLETION_MARKER))?;
 
-    let buf_reader = BufReader::new(response);
-    let deflater = GzDecoder::new(buf_reader);
-    let mut archive = Archive::new(deflater);
+        // a directory without the completion marker
+        // is a leftover from an older interrupted download
+        if root_directory.exists() {
+            remove_dir_all(root_directory)?;
+        }
 
-    archive.unpack(destination_dir)?;
-    debug!("done!");
+        rename(&partial_directory, root_directory)?;
+
+        Ok(())
+    });
+
+    if result.is_err() && partial_directory.exists() {
+        if let Err(error) = remove_dir_all(&partial_directory) {
+            warn!(
+                "failed to remove {}: {}",
+                partial_directory.display(),
+                error
+            );
+        }
+    }
+
+    result
+}
+
+/// Removes partially unpacked versions left over by interrupted downloads.
+/// Entries that can't be cleaned up are logged and skipped.
+pub fn remove_incomplete_downloads<P: AsRef<Path>>(cache_directory: P) -> io::Result<()> {
+    let cache_directory = cache_directory.as_ref();
+
+    if !cache_directory.exists() {
+        return Ok(());
+    }
+
+    for package_entry in read_dir(cache_directory)? {
+        let result = package_entry.and_then(|package_entry| {
+            if package_entry.file_type()?.is_dir() {
+                remove_incomplete_versions(&package_entry.path());
+            }
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            warn!("failed to read {}: {}", cache_directory.display(), error);
+        }
+    }
 
     Ok(())
 }
 
-impl TarballUrl {
-    pub fn to_string(&self) -> String {
-        self.0.clone()
+fn remove_incomplete_versions(package_directory: &Path) {
+    let entries = match read_dir(package_directory) {
+        Ok(entries) => entries,
+        Err(error) => {
+            warn!("failed to read {}: {}", package_directory.display(), error);
+            return;
+        }
+    };
+
+    for version_entry in entries {
+        let result = version_entry.and_then(|version_entry| {
+            if version_entry.file_type()?.is_dir() {
+                remove_incomplete_version(&version_entry)
+            } else {
+                Ok(())
+            }
+        });
+
+        if let Err(error) = result {
+            warn!(
+                "failed to clean up a version in {}: {}",
+                package_directory.display(),
+                error
+            );
+        }
+    }
+}
+
+fn remove_incomplete_version(version_entry: &DirEntry) -> io::Result<()> {
+    let version_directory = version_entry.path();
+    let marker = version_directory.join(COMPLETION_MARKER);
+
+    let is_partial = version_entry
+        .file_name()
+        .to_string_lossy()
+        .contains(PARTIAL_SUFFIX);
+
+    if is_partial {
+        info!(
+            "removing incomplete download {}",
+            version_directory.display()
+        );
+        return remove_dir_all(&version_directory);
+    }
+
+    if marker.exists() {
+        return Ok(());
+    }
+
+    // an interrupted download leaves a package directory without a marker,
+    // which is downloaded again rather than served half-populated
+    info!(
+        "removing incomplete download {}",
+        version_directory.display()
+    );
+    remove_dir_all(&version_directory)
+}
+
+impl VersionManifest {
+    pub fn is_prerelease(&self) -> bool {
+        !self.version.pre.is_empty()
+    }
+}
+
+impl fmt::Display for TarballUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
     }
 }
 
 impl PackageManifest {
-    pub fn new(source: &NpmPackageManifest, registry_url: String) -> Self {
+    pub fn new(source: &NpmPackageManifest, package_config: &PackageConfig) -> Self {
         let mut versions: Vec<VersionManifest> = source
             .versions
             .iter()
-            .filter_map(|(version_id, _)| {
-                let version_info = source.versions.get(version_id)?;
-                let published = source.time.get(version_id)?.parse::<DateTime<Utc>>().ok()?;
+            .filter_map(|(version_id, version_info)| {
+                // abbreviated manifests have no times at all,
+                // full manifests are expected to list every version
+                let published = match source.time.get(version_id) {
+                    Some(time) => Some(time.parse::<DateTime<Utc>>().ok()?),
+                    None if source.time.is_empty() => None,
+                    None => return None,
+                };
                 let parsed_version = Version::parse(version_id).ok()?;
 
-                if parsed_version.pre != Prerelease::EMPTY {
+                if !package_config.accepts_version(&parsed_version) {
                     return None;
                 }
 
@@ -177,6 +611,10 @@ impl PackageManifest {
                     version: parsed_version,
                     published,
                     tarball_url: version_info.dist.tarball.clone(),
+                    expected_digest: ExpectedDigest::from_dist(
+                        version_info.dist.integrity.as_deref(),
+                        version_info.dist.shasum.as_deref(),
+                    ),
                 })
             })
             .collect();
@@ -185,8 +623,11 @@ impl PackageManifest {
 
         let mut result = PackageManifest {
             versions: vec![],
-            registry_url,
+            // only used in error messages
+            registry_url: redact_credentials(&package_config.registry),
             lookup: HashMap::new(),
+            dist_tags: source.dist_tags.clone(),
+            validators: ManifestValidators::default(),
         };
 
         for version in versions {
@@ -199,104 +640,1409 @@ impl PackageManifest {
         result
     }
 
-    pub fn get_tarball_url(&self, version: &str) -> Option<&TarballUrl> {
-        self.lookup.get(version).map(|version| &version.tarball_url)
+    /// Rough estimate of the memory the manifest takes.
+    pub fn approximate_size(&self) -> usize {
+        let versions: usize = self
+            .versions
+            .iter()
+            .map(|version| {
+                // the version string is stored twice, as a field and a lookup key
+                2 * version.version_str.len()
+                    + version.tarball_url.0.len()
+                    + version
+                        .expected_digest
+                        .as_ref()
+                        .map_or(0, |digest| digest.digest.len())
+                    + size_of::<VersionManifest>()
+                    + size_of::<String>()
+                    + 2 * size_of::<Arc<VersionManifest>>()
+            })
+            .sum();
+
+        let dist_tags: usize = self
+            .dist_tags
+            .iter()
+            .map(|(tag, version)| tag.len() + version.len() + 2 * size_of::<String>())
+            .sum();
+
+        size_of::<Self>() + self.registry_url.len() + versions + dist_tags
+    }
+
+    pub fn latest_stable(&self) -> Option<&Arc<VersionManifest>> {
+        self.versions
+            .iter()
+            .find(|version| !version.is_prerelease())
+    }
+
+    pub fn get_version(&self, version: &str) -> Option<&Arc<VersionManifest>> {
+        self.lookup.get(version)
+    }
+
+    /// Resolves an exact version, a dist-tag (`latest`, `next`) or
+    /// a semver range (`^18`, `~17.0`, `18.x`) to the highest known
+    /// version satisfying it.
+    pub fn resolve_version(&self, spec: &str) -> Option<&Arc<VersionManifest>> {
+        if let Some(version) = self.lookup.get(spec) {
+            return Some(version);
+        }
+
+        if let Some(tagged_version) = self.dist_tags.get(spec) {
+            return self.lookup.get(tagged_version);
+        }
+
+        // an exact version that is not in the lookup table does not exist,
+        // it must not be treated as an implicit caret requirement
+        if Version::parse(spec).is_ok() {
+            return None;
+        }
+
+        let requirement = VersionReq::parse(spec).ok()?;
+
+        // versions are sorted in descending order
+        self.versions
+            .iter()
+            .find(|version| requirement.matches(&version.version))
     }
 }
 
-impl ManifestRepository {
-    pub fn new() -> Self {
-        ManifestRepository {
-            cache: Cache::new(Duration::from_millis(15000)),
-            coalescer: Coalescer::new(),
+fn manifest_key(package_config: &PackageConfig, detail: ManifestDetail) -> String {
+    let mut key = package_config.key();
+
+    // versions are filtered by the prerelease settings, aliases
+    // of a package with different settings can't share manifests
+    if package_config.include_prereleases {
+        key.push_str(" (prereleases");
+
+        if let Some(filter) = package_config.prerelease_filter.as_ref() {
+            key.push(' ');
+            key.push_str(filter);
         }
+
+        key.push(')');
     }
 
-    pub fn get_manifest(
+    if detail == ManifestDetail::Abbreviated {
+        key.push_str(" (abbreviated)");
+    }
+
+    key
+}
+
+impl ManifestStore {
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(format!("{}.json", filter_string(key)))
+    }
+
+    fn persist(
+        &self,
+        key: &str,
+        manifest: NpmPackageManifest,
+        validators: ManifestValidators,
+    ) -> Result<(), NpmPackageServerError> {
+        let fetched_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+
+        let persisted = PersistedManifest {
+            fetched_at,
+            validators,
+            manifest,
+        };
+
+        let path = self.path(key);
+        let partial_path = path.with_extension(format!(
+            "json{}{:08x}",
+            PARTIAL_SUFFIX,
+            thread_rng().next_u32()
+        ));
+
+        create_dir_all(&self.directory)?;
+
+        let result = File::create(&partial_path)
+            .map_err(NpmPackageServerError::from)
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                serde_json::to_writer(&mut writer, &persisted)?;
+                // dropping the writer would ignore errors of the last write
+                writer.flush()?;
+                Ok(())
+            })
+            .and_then(|_| Ok(rename(&partial_path, &path)?));
+
+        if result.is_err() {
+            let _ = remove_file(&partial_path);
+        }
+
+        result
+    }
+
+    fn forget(&self, key: &str) -> Result<(), NpmPackageServerError> {
+        self.cache.remove(key)?;
+
+        match remove_file(self.path(key)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn restore(
+        &self,
+        package_config: &PackageConfig,
+        key: String,
+    ) -> Result<(), NpmPackageServerError> {
+        let path = self.path(&key);
+
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let persisted: PersistedManifest =
+            serde_json::from_reader(BufReader::new(File::open(&path)?))?;
+
+        let fetched_at = SystemTime::UNIX_EPOCH + Duration::from_secs(persisted.fetched_at);
+        let age = SystemTime::now()
+            .duration_since(fetched_at)
+            .unwrap_or_default();
+
+        let mut package_manifest = PackageManifest::new(&persisted.manifest, package_config);
+        package_manifest.validators = persisted.validators;
+
+        debug!("restored the manifest of \"{}\" fetched {:?} ago", key, age);
+
+        self.cache
+            .set_with_age(key, Arc::new(package_manifest), age)?;
+
+        Ok(())
+    }
+
+    fn fetch(
         &self,
         package_config: &PackageConfig,
+        detail: ManifestDetail,
+        key: String,
+        ttl: Duration,
     ) -> Result<Arc<PackageManifest>, NpmPackageServerError> {
-        let key = package_config.key();
+        if let Some(entry) = self.errors.get(&key, None)? {
+            debug!("not fetching \"{}\", it failed recently", key);
+            return Err(entry.value.into());
+        }
 
-        let coalesced_result = self.coalescer.execute(key.clone(), move || {
-            let cached_entry = self.cache.get(&key, None)?;
+        let coalesced_result = self.coalescer.execute(key.clone(), || {
+            let key = key.clone();
+            let stale_entry = self.cache.get_stale(&key)?;
 
-            if let Some(entry) = cached_entry {
-                return Ok(Arc::clone(&entry.value));
+            // another caller might have refreshed it while this one was waiting
+            if let Some(entry) = stale_entry.as_ref() {
+                if entry.updated.elapsed() <= ttl {
+                    return Ok(Arc::clone(&entry.value));
+                }
             }
 
-            let manifest = fetch_manifest(package_config)?;
-            let cached_entry = Arc::new(PackageManifest::new(
-                &manifest,
-                package_config.registry.clone(),
-            ));
+            let validators = stale_entry.as_ref().map(|entry| &entry.value.validators);
+
+            let (manifest, validators) =
+                match fetch_manifest(&self.http_clients, package_config, detail, validators)? {
+                    Some(result) => result,
+                    None => {
+                        // only possible if validators were sent, i.e. there is a stale entry
+                        if let Some(entry) = stale_entry {
+                            self.cache.touch(&key)?;
+                            return Ok(entry.value);
+                        }
+
+                        return Err(ManifestFetchError::UnderlyingRequestError(String::from(
+                            "unexpected 304 Not Modified",
+                        ))
+                        .into());
+                    }
+                };
+
+            let mut package_manifest = PackageManifest::new(&manifest, package_config);
+            package_manifest.validators = validators.clone();
+
+            let cached_entry = Arc::new(package_manifest);
+
+            self.cache.set(key.clone(), Arc::clone(&cached_entry))?;
 
-            self.cache.set(key, Arc::clone(&cached_entry))?;
+            // abbreviated requests are served from the full manifest from now on
+            if detail == ManifestDetail::Full && package_config.abbreviated_manifest {
+                let abbreviated_key = manifest_key(package_config, ManifestDetail::Abbreviated);
+
+                if let Err(error) = self.forget(&abbreviated_key) {
+                    warn!("failed to remove \"{}\": {}", abbreviated_key, error);
+                }
+            }
+
+            // a 304 doesn't update the persisted copy, so after a restart
+            // it's considered older than it is and revalidated sooner
+            if let Err(error) = self.persist(&key, manifest, validators) {
+                warn!("failed to persist the manifest of \"{}\": {}", key, error);
+            }
 
             Ok(cached_entry)
         });
 
-        match coalesced_result {
+        let result = match coalesced_result {
             Ok(result) => result,
             Err(err) => Err(err.into()),
+        };
+
+        match result.as_ref() {
+            Ok(_) => {
+                lock(&self.failing).remove(&key);
+            }
+            Err(error) => {
+                if let NpmPackageServerError::ManifestFetchError(error) = error {
+                    if self.error_ttl > Duration::from_secs(0) {
+                        self.errors.set(key.clone(), error.clone())?;
+                    }
+                }
+
+                lock(&self.failing).insert(key);
+            }
         }
+
+        result
     }
-}
 
-impl DownloadManager {
-    pub fn new() -> Self {
-        DownloadManager {
-            coalescer: Coalescer::new(),
+    fn status(&self, key: &str) -> ManifestStatus {
+        if lock(&self.failing).contains(key) {
+            ManifestStatus::Degraded
+        } else {
+            ManifestStatus::Fresh
         }
     }
+}
 
-    pub fn get_download_paths(
-        config: &PackageConfig,
-        request: &PackageFileRequest,
-    ) -> DownloadPaths {
-        let mut path = PathBuf::new();
-
-        path.push(".");
-        path.push(".tmp");
-        path.push(config.identifier_safe_key());
-        path.push(&request.version);
+impl Drop for RefreshGuard {
+    fn drop(&mut self) {
+        lock(&self.store.refreshing).remove(&self.key);
+    }
+}
 
-        let root_directory = path.clone();
+impl ManifestRepository {
+    /// `ttl` applies to packages without their own `manifest_ttl`,
+    /// `error_ttl` is how long failed fetches aren't retried for.
+    pub fn new(
+        cache_directory: &Path,
+        http_clients: Arc<HttpClients>,
+        ttl: Duration,
+        max_age: Duration,
+        error_ttl: Duration,
+        limits: &CacheLimits,
+        coalescing_timeout: Option<Duration>,
+    ) -> Self {
+        ManifestRepository {
+            store: Arc::new(ManifestStore {
+                directory: cache_directory.join(MANIFEST_DIRECTORY),
+                http_clients,
+                // expired manifests are the last good copies served
+                // on registry errors, so only the limits remove them
+                cache: Cache::new(ttl).with_retention(None).with_limits(
+                    limits.max_entries,
+                    limits.max_bytes,
+                    |manifest| manifest.approximate_size(),
+                ),
+                coalescer: Coalescer::new().with_timeout(coalescing_timeout),
+                refreshing: Mutex::new(HashSet::new()),
+                failing: Mutex::new(HashSet::new()),
+                errors: Cache::new(error_ttl),
+                error_ttl,
+            }),
+            ttl,
+            max_age,
+        }
+    }
 
-        path.push("package");
+    pub fn get_manifest(
+        &self,
+        package_config: &PackageConfig,
+        detail: ManifestDetail,
+    ) -> Result<Arc<PackageManifest>, NpmPackageServerError> {
+        let (manifest, _) = self.get_manifest_with_status(package_config, detail)?;
+        Ok(manifest)
+    }
 
-        let package_directory = path.clone();
+    /// Falls back to the last successfully fetched manifest
+    /// of any age if the registry can't be reached.
+    pub fn get_manifest_with_status(
+        &self,
+        package_config: &PackageConfig,
+        detail: ManifestDetail,
+    ) -> Result<(Arc<PackageManifest>, ManifestStatus), NpmPackageServerError> {
+        let ttl = package_config.manifest_ttl().unwrap_or(self.ttl);
 
-        let requested_file_name = if request.path == "" {
-            &config.index_file
+        let detail = if package_config.abbreviated_manifest {
+            self.available_detail(package_config, detail, ttl)?
         } else {
-            &request.path
+            ManifestDetail::Full
         };
 
-        path.push(requested_file_name);
+        let key = manifest_key(package_config, detail);
 
-        DownloadPaths {
-            root_directory,
-            package_directory,
-            requested_file_path: path,
+        // a single lookup, so that the cache metrics count one hit or miss per call
+        if let Some(entry) = self
+            .store
+            .cache
+            .get(&key, Some(ttl.saturating_add(self.max_age)))?
+        {
+            let status = self.store.status(&key);
+
+            if entry.updated.elapsed() > ttl {
+                self.refresh_in_background(package_config, detail, key, ttl);
+            }
+
+            return Ok((entry.value, status));
+        }
+
+        match self.store.fetch(package_config, detail, key.clone(), ttl) {
+            Ok(manifest) => Ok((manifest, ManifestStatus::Fresh)),
+            Err(error) => match self.store.cache.get_stale(&key)? {
+                Some(entry) => {
+                    warn!(
+                        "serving a stale manifest of \"{}\": {}",
+                        package_config.name, error
+                    );
+                    Ok((entry.value, ManifestStatus::Degraded))
+                }
+                None => Err(error),
+            },
         }
     }
 
-    pub fn download<P: AsRef<Path>>(
+    // a full manifest has everything the abbreviated one does, so one that can
+    // still be served is used instead of fetching and caching both formats
+    fn available_detail(
         &self,
-        config: &PackageConfig,
-        tarball_url: &TarballUrl,
-        root_directory: P,
-    ) -> Result<(), NpmPackageServerError> {
-        let key = tarball_url.to_string();
+        package_config: &PackageConfig,
+        detail: ManifestDetail,
+        ttl: Duration,
+    ) -> Result<ManifestDetail, NpmPackageServerError> {
+        if detail == ManifestDetail::Full {
+            return Ok(detail);
+        }
 
-        let coalesced_result = self.coalescer.execute(key.clone(), || {
-            return download_and_unpack_tarball(tarball_url, root_directory, config);
-        });
+        let full_key = manifest_key(package_config, ManifestDetail::Full);
 
-        match coalesced_result? {
-            Ok(result) => Ok(result),
-            Err(err) => Err(err.into()),
+        match self.store.cache.get_stale(&full_key)? {
+            Some(entry) if entry.updated.elapsed() <= ttl.saturating_add(self.max_age) => {
+                Ok(ManifestDetail::Full)
+            }
+            _ => Ok(detail),
         }
     }
+
+    /// Loads the manifests persisted by previous runs into the cache.
+    pub fn restore(&self, packages: &[PackageConfig]) {
+        let mut restored: HashSet<String> = HashSet::new();
+
+        for package_config in packages.iter() {
+            let mut details = vec![ManifestDetail::Full];
+
+            if package_config.abbreviated_manifest {
+                details.push(ManifestDetail::Abbreviated);
+            }
+
+            for detail in details {
+                let key = manifest_key(package_config, detail);
+
+                // aliases with the same settings share manifests
+                if !restored.insert(key.clone()) {
+                    continue;
+                }
+
+                if let Err(error) = self.store.restore(package_config, key) {
+                    warn!(
+                        "failed to restore the manifest of \"{}\": {}",
+                        package_config.name, error
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn cache_stats(&self) -> Result<CacheStats, CachingError> {
+        self.store.cache.stats()
+    }
+
+    pub fn purge(&self) -> Result<(), CachingError> {
+        self.store.cache.purge()?;
+        self.store.errors.purge()
+    }
+
+    fn refresh_in_background(
+        &self,
+        package_config: &PackageConfig,
+        detail: ManifestDetail,
+        key: String,
+        ttl: Duration,
+    ) {
+        if !lock(&self.store.refreshing).insert(key.clone()) {
+            return;
+        }
+
+        let guard = RefreshGuard {
+            store: Arc::clone(&self.store),
+            key: key.clone(),
+        };
+
+        let package_config = package_config.clone();
+
+        thread::spawn(move || {
+            debug!("refreshing \"{}\" in the background", key);
+
+            // the stale entry stays in the cache if the refresh fails
+            if let Err(error) = guard.store.fetch(&package_config, detail, key, ttl) {
+                warn!(
+                    "failed to refresh the manifest of \"{}\": {}",
+                    package_config.name, error
+                );
+            }
+
+            drop(guard);
+        });
+    }
+}
+
+impl DownloadPaths {
+    pub fn is_unpacked(&self) -> bool {
+        let storage_path = match self.storage {
+            StorageMode::Unpacked => &self.package_directory,
+            StorageMode::Tarball => &self.index_path,
+        };
+
+        self.root_directory.join(COMPLETION_MARKER).exists() && storage_path.exists()
+    }
+}
+
+impl DownloadManager {
+    pub fn new(
+        cache_directory: PathBuf,
+        http_clients: Arc<HttpClients>,
+        index_limits: &CacheLimits,
+        coalescing_timeout: Option<Duration>,
+    ) -> Self {
+        DownloadManager {
+            cache_directory,
+            http_clients,
+            coalescer: Coalescer::new().with_timeout(coalescing_timeout),
+            indexes: Cache::new(Duration::from_secs(300)).with_limits(
+                index_limits.max_entries,
+                index_limits.max_bytes,
+                |index| index.approximate_size(),
+            ),
+            leases: Mutex::new(HashMap::new()),
+            access_times: Mutex::new(HashMap::new()),
+            sizes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get_download_paths(
+        &self,
+        config: &PackageConfig,
+        request: &PackageFileRequest,
+    ) -> DownloadPaths {
+        let mut path = self.cache_directory.clone();
+
+        path.push(config.identifier_safe_key());
+
+        match config.storage {
+            StorageMode::Unpacked => path.push(&request.version),
+            StorageMode::Tarball => {
+                path.push(format!("{}{}", request.version, TARBALL_DIRECTORY_SUFFIX))
+            }
+        }
+
+        DownloadPaths {
+            storage: config.storage,
+            package_directory: path.join(PACKAGE_DIRECTORY),
+            tarball_path: path.join(TARBALL_FILE_NAME),
+            index_path: path.join(INDEX_FILE_NAME),
+            root_directory: path,
+        }
+    }
+
+    /// Provides access to the files of an already downloaded version
+    /// and marks it as recently used.
+    pub fn open(
+        &self,
+        download_paths: &DownloadPaths,
+    ) -> Result<Box<dyn PackageFiles>, NpmPackageServerError> {
+        // only versions that exist are recorded, requests for made up
+        // versions would otherwise grow the map without bounds
+        if download_paths.is_unpacked() {
+            lock(&self.access_times)
+                .insert(download_paths.root_directory.clone(), SystemTime::now());
+        }
+
+        match download_paths.storage {
+            StorageMode::Unpacked => Ok(Box::new(UnpackedPackage::new(
+                download_paths.package_directory.clone(),
+            ))),
+            StorageMode::Tarball => {
+                let key = download_paths.index_path.to_string_lossy().into_owned();
+
+                let index = match self.indexes.get(&key, None)? {
+                    Some(entry) => entry.value,
+                    None => {
+                        let index_file = BufReader::new(File::open(&download_paths.index_path)?);
+                        let index: Arc<TarballIndex> =
+                            Arc::new(serde_json::from_reader(index_file)?);
+                        self.indexes.set(key, Arc::clone(&index))?;
+                        index
+                    }
+                };
+
+                Ok(Box::new(TarballPackage::new(
+                    &download_paths.tarball_path,
+                    PACKAGE_DIRECTORY,
+                    index,
+                )))
+            }
+        }
+    }
+
+    pub fn index_cache_stats(&self) -> Result<CacheStats, CachingError> {
+        self.indexes.stats()
+    }
+
+    pub fn purge(&self) -> Result<(), CachingError> {
+        self.indexes.purge()
+    }
+
+    pub fn remove_incomplete_downloads(&self) -> io::Result<()> {
+        remove_incomplete_downloads(&self.cache_directory)
+    }
+
+    /// Keeps the version from being evicted until the lease is dropped.
+    pub fn lease(&self, download_paths: &DownloadPaths) -> VersionLease<'_> {
+        let root_directory = download_paths.root_directory.clone();
+
+        *lock(&self.leases)
+            .entry(root_directory.clone())
+            .or_insert(0) += 1;
+
+        VersionLease {
+            manager: self,
+            root_directory,
+        }
+    }
+
+    fn is_in_use(&self, leases: &HashMap<PathBuf, usize>, root_directory: &Path) -> bool {
+        if leases.contains_key(root_directory) {
+            return true;
+        }
+
+        let key = root_directory.to_string_lossy().into_owned();
+
+        // treat errors as "in use", it's always safe to keep a version
+        self.coalescer.is_inflight(&key).unwrap_or(true)
+    }
+
+    fn cached_versions(&self) -> io::Result<Vec<CachedVersion>> {
+        let cache_directory = &self.cache_directory;
+        let mut versions = vec![];
+
+        if !cache_directory.exists() {
+            return Ok(versions);
+        }
+
+        for package_entry in read_dir(cache_directory)? {
+            let package_entry = package_entry?;
+
+            if !package_entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let package_key = package_entry.file_name().to_string_lossy().into_owned();
+
+            for version_entry in read_dir(package_entry.path())? {
+                let version_entry = version_entry?;
+                let root_directory = version_entry.path();
+                let marker = root_directory.join(COMPLETION_MARKER);
+
+                let is_partial = version_entry
+                    .file_name()
+                    .to_string_lossy()
+                    .contains(PARTIAL_SUFFIX);
+
+                if !version_entry.file_type()?.is_dir() || is_partial || !marker.exists() {
+                    continue;
+                }
+
+                let last_access = match lock(&self.access_times).get(&root_directory) {
+                    Some(last_access) => *last_access,
+                    None => marker.metadata()?.modified()?,
+                };
+
+                let cached_size = lock(&self.sizes).get(&root_directory).copied();
+
+                let size = match cached_size {
+                    Some(size) => size,
+                    None => {
+                        let size = directory_size(&root_directory)?;
+                        lock(&self.sizes).insert(root_directory.clone(), size);
+                        size
+                    }
+                };
+
+                versions.push(CachedVersion {
+                    package_key: package_key.clone(),
+                    path: root_directory,
+                    size,
+                    last_access,
+                });
+            }
+        }
+
+        Ok(versions)
+    }
+
+    fn remove_version(&self, version: &CachedVersion) -> io::Result<()> {
+        let file_name = match version.path.file_name() {
+            Some(file_name) => file_name.to_string_lossy().into_owned(),
+            None => return Ok(()),
+        };
+
+        let evicted_path = version.path.with_file_name(format!(
+            "{}{}{:08x}",
+            file_name,
+            PARTIAL_SUFFIX,
+            thread_rng().next_u32()
+        ));
+
+        {
+            // the version might have been leased since it was selected,
+            // renaming under the lock guarantees that new requests
+            // either keep it or don't see it at all
+            let leases = lock(&self.leases);
+
+            if self.is_in_use(&leases, &version.path) {
+                return Ok(());
+            }
+
+            rename(&version.path, &evicted_path)?;
+        }
+
+        info!(
+            "evicting {} ({} bytes)",
+            version.path.display(),
+            version.size
+        );
+
+        lock(&self.access_times).remove(&version.path);
+        lock(&self.sizes).remove(&version.path);
+
+        if let Err(error) = self
+            .indexes
+            .remove(&version.path.join(INDEX_FILE_NAME).to_string_lossy())
+        {
+            warn!("failed to drop the cached index: {}", error);
+        }
+
+        // leftovers are cleaned up by remove_incomplete_downloads on startup
+        remove_dir_all(&evicted_path)
+    }
+
+    /// Removes the least recently used versions until the cache directory
+    /// fits into `Config::disk_quota` and every package into its `disk_quota`.
+    pub fn evict(&self, config: &Config) -> io::Result<()> {
+        let mut package_quotas: HashMap<String, u64> = HashMap::new();
+
+        for package in config.packages.iter() {
+            if let Some(quota) = package.disk_quota {
+                // aliases of the same package share the directory
+                let entry = package_quotas
+                    .entry(package.identifier_safe_key())
+                    .or_insert(quota);
+                *entry = quota.min(*entry);
+            }
+        }
+
+        if config.disk_quota.is_none() && package_quotas.is_empty() {
+            return Ok(());
+        }
+
+        let versions = self.cached_versions()?;
+
+        let selected = {
+            let leases = lock(&self.leases);
+
+            select_for_eviction(versions, config.disk_quota, &package_quotas, |version| {
+                self.is_in_use(&leases, &version.path)
+            })
+        };
+
+        // a version that can't be removed mustn't keep the others from being evicted
+        for version in selected.iter() {
+            if let Err(error) = self.remove_version(version) {
+                warn!("failed to evict {}: {}", version.path.display(), error);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn download(
+        &self,
+        config: &PackageConfig,
+        version: &VersionManifest,
+        download_paths: &DownloadPaths,
+    ) -> Result<(), NpmPackageServerError> {
+        let key = download_paths.root_directory.to_string_lossy().into_owned();
+
+        let coalesced_result = self.coalescer.execute(key, || {
+            download_and_unpack_tarball_atomically(
+                &self.http_clients,
+                version,
+                download_paths,
+                config,
+            )
+        });
+
+        match coalesced_result? {
+            Ok(result) => Ok(result),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for VersionLease<'_> {
+    fn drop(&mut self) {
+        let mut leases = lock(&self.manager.leases);
+
+        if let Some(count) = leases.get_mut(&self.root_directory) {
+            *count -= 1;
+
+            if *count == 0 {
+                leases.remove(&self.root_directory);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::test_server::{response, TestServer};
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    fn create_package_config(extra: &str) -> PackageConfig {
+        let mut config: PackageConfig = toml::from_str(&format!(
+            "name = \"react\"\nregistry = \"https://r/\"\n{}",
+            extra
+        ))
+        .unwrap();
+        config.prepare().unwrap();
+        config
+    }
+
+    fn create_manifest(package_config: &PackageConfig) -> PackageManifest {
+        let source: NpmPackageManifest = from_str(
+            r#"{
+                "dist-tags": { "latest": "18.2.0", "legacy": "17.0.2", "next": "19.0.0-rc.1" },
+                "versions": {
+                    "17.0.1": { "dist": { "tarball": "https://r/react-17.0.1.tgz" } },
+                    "17.0.2": { "dist": { "tarball": "https://r/react-17.0.2.tgz" } },
+                    "18.0.0": { "dist": { "tarball": "https://r/react-18.0.0.tgz" } },
+                    "18.2.0": { "dist": { "tarball": "https://r/react-18.2.0.tgz" } },
+                    "19.0.0": { "dist": { "tarball": "https://r/react-19.0.0.tgz" } },
+                    "19.0.0-rc.1": { "dist": { "tarball": "https://r/react-19.0.0-rc.1.tgz" } },
+                    "19.1.0-canary.5": { "dist": { "tarball": "https://r/react-19.1.0-canary.5.tgz" } }
+                },
+                "time": {
+                    "17.0.1": "2020-10-22T12:00:00.000Z",
+                    "17.0.2": "2021-03-22T12:00:00.000Z",
+                    "18.0.0": "2022-03-29T12:00:00.000Z",
+                    "18.2.0": "2022-06-14T12:00:00.000Z",
+                    "19.0.0": "2024-12-05T12:00:00.000Z",
+                    "19.0.0-rc.1": "2024-04-25T12:00:00.000Z",
+                    "19.1.0-canary.5": "2025-01-10T12:00:00.000Z"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        PackageManifest::new(&source, package_config)
+    }
+
+    #[test]
+    fn test_removes_incomplete_downloads() {
+        let cache_directory = create_cache_directory();
+
+        let package_directory = cache_directory.join("explorer_react");
+        let complete = package_directory.join("1.0.0");
+        let legacy = package_directory.join("1.1.0");
+        let partial = package_directory.join("1.2.0.partial-0000abcd");
+        let empty = package_directory.join("1.3.0");
+
+        for directory in [&complete, &legacy, &partial].iter() {
+            create_dir_all(directory.join("package")).unwrap();
+        }
+
+        create_dir_all(&empty).unwrap();
+        File::create(complete.join(COMPLETION_MARKER)).unwrap();
+        File::create(partial.join(COMPLETION_MARKER)).unwrap();
+
+        remove_incomplete_downloads(&cache_directory).unwrap();
+
+        assert!(complete.join("package").exists());
+        assert!(!legacy.exists());
+        assert!(!partial.exists());
+        assert!(!empty.exists());
+
+        remove_dir_all(cache_directory).unwrap();
+    }
+
+    #[test]
+    fn test_only_records_access_to_opened_versions() {
+        let cache_directory = create_cache_directory();
+        let manager = DownloadManager::new(
+            cache_directory.clone(),
+            Arc::new(HttpClients::new(HttpConfig::default())),
+            &CacheLimits::default(),
+            None,
+        );
+
+        let request = PackageFileRequest {
+            name: String::from("react"),
+            version: String::from("1.2.3-made-up"),
+            path: String::new(),
+        };
+        let download_paths = manager.get_download_paths(&create_package_config(""), &request);
+
+        drop(manager.lease(&download_paths));
+        drop(manager.open(&download_paths));
+
+        assert!(lock(&manager.access_times).is_empty());
+
+        create_dir_all(&download_paths.package_directory).unwrap();
+        File::create(download_paths.root_directory.join(COMPLETION_MARKER)).unwrap();
+
+        manager.open(&download_paths).unwrap();
+
+        assert!(lock(&manager.access_times).contains_key(&download_paths.root_directory));
+
+        remove_dir_all(cache_directory).unwrap();
+    }
+
+    fn version_strings(manifest: &PackageManifest) -> Vec<String> {
+        manifest
+            .versions
+            .iter()
+            .map(|version| version.version.to_string())
+            .collect()
+    }
+
+    fn resolve(manifest: &PackageManifest, spec: &str) -> Option<String> {
+        manifest
+            .resolve_version(spec)
+            .map(|version| version.version.to_string())
+    }
+
+    #[test]
+    fn test_resolves_exact_version() {
+        let manifest = create_manifest(&create_package_config(""));
+        assert_eq!(resolve(&manifest, "18.0.0"), Some("18.0.0".into()));
+        assert_eq!(resolve(&manifest, "18.1.0"), None);
+    }
+
+    #[test]
+    fn test_resolves_dist_tags() {
+        let manifest = create_manifest(&create_package_config(""));
+        assert_eq!(resolve(&manifest, "latest"), Some("18.2.0".into()));
+        assert_eq!(resolve(&manifest, "legacy"), Some("17.0.2".into()));
+        assert_eq!(resolve(&manifest, "beta"), None);
+    }
+
+    #[test]
+    fn test_resolves_ranges() {
+        let manifest = create_manifest(&create_package_config(""));
+        assert_eq!(resolve(&manifest, "^18"), Some("18.2.0".into()));
+        assert_eq!(resolve(&manifest, "~17.0"), Some("17.0.2".into()));
+        assert_eq!(resolve(&manifest, "18.x"), Some("18.2.0".into()));
+        assert_eq!(resolve(&manifest, "*"), Some("19.0.0".into()));
+        assert_eq!(resolve(&manifest, "^20"), None);
+    }
+
+    #[test]
+    fn test_excludes_prereleases_by_default() {
+        let manifest = create_manifest(&create_package_config(""));
+
+        assert_eq!(
+            version_strings(&manifest),
+            vec!["19.0.0", "18.2.0", "18.0.0", "17.0.2", "17.0.1"]
+        );
+        assert_eq!(resolve(&manifest, "next"), None);
+        assert_eq!(resolve(&manifest, "19.0.0-rc.1"), None);
+    }
+
+    #[test]
+    fn test_includes_prereleases() {
+        let manifest = create_manifest(&create_package_config("include_prereleases = true"));
+
+        assert_eq!(
+            version_strings(&manifest),
+            vec![
+                "19.1.0-canary.5",
+                "19.0.0",
+                "19.0.0-rc.1",
+                "18.2.0",
+                "18.0.0",
+                "17.0.2",
+                "17.0.1"
+            ]
+        );
+        assert_eq!(resolve(&manifest, "next"), Some("19.0.0-rc.1".into()));
+        assert_eq!(
+            manifest
+                .latest_stable()
+                .map(|version| version.version.to_string()),
+            Some("19.0.0".into())
+        );
+    }
+
+    #[test]
+    fn test_filters_prereleases() {
+        let manifest = create_manifest(&create_package_config(
+            "include_prereleases = true\nprerelease_filter = \"-rc\\\\.\"",
+        ));
+
+        assert_eq!(
+            version_strings(&manifest),
+            vec![
+                "19.0.0",
+                "19.0.0-rc.1",
+                "18.2.0",
+                "18.0.0",
+                "17.0.2",
+                "17.0.1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reads_abbreviated_manifests() {
+        let source: NpmPackageManifest = from_str(
+            r#"{
+                "name": "react",
+                "modified": "2022-06-14T12:00:00.000Z",
+                "dist-tags": { "latest": "18.2.0" },
+                "versions": {
+                    "18.0.0": { "dist": { "tarball": "https://r/react-18.0.0.tgz" } },
+                    "18.2.0": { "dist": { "tarball": "https://r/react-18.2.0.tgz" } }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let manifest = PackageManifest::new(&source, &create_package_config(""));
+
+        assert_eq!(version_strings(&manifest), vec!["18.2.0", "18.0.0"]);
+        assert_eq!(resolve(&manifest, "latest"), Some("18.2.0".into()));
+        assert!(manifest
+            .versions
+            .iter()
+            .all(|version| version.published.is_none()));
+    }
+
+    #[test]
+    fn test_separates_manifests_by_prerelease_settings() {
+        let stable = create_package_config("");
+        let prereleases = create_package_config("include_prereleases = true");
+        let filtered =
+            create_package_config("include_prereleases = true\nprerelease_filter = \"-rc\"");
+
+        let keys: HashSet<String> = [&stable, &prereleases, &filtered]
+            .iter()
+            .flat_map(|config| {
+                vec![
+                    manifest_key(config, ManifestDetail::Full),
+                    manifest_key(config, ManifestDetail::Abbreviated),
+                ]
+            })
+            .collect();
+
+        assert_eq!(keys.len(), 6);
+    }
+
+    fn create_cache_directory() -> PathBuf {
+        let mut cache_directory = std::env::temp_dir();
+        cache_directory.push(format!(
+            "npm-package-explorer-test-{}",
+            thread_rng().next_u64()
+        ));
+        create_dir_all(&cache_directory).unwrap();
+        cache_directory
+    }
+
+    fn create_repository(
+        cache_directory: &Path,
+        ttl: Duration,
+        max_age: Duration,
+    ) -> ManifestRepository {
+        ManifestRepository::new(
+            cache_directory,
+            Arc::new(HttpClients::new(HttpConfig::default())),
+            ttl,
+            max_age,
+            Duration::from_secs(10),
+            &CacheLimits::default(),
+            None,
+        )
+    }
+
+    fn manifest_body(version: &str) -> String {
+        format!(
+            r#"{{ "versions": {{ "{0}": {{ "dist": {{ "tarball": "https://r/react-{0}.tgz" }} }} }} }}"#,
+            version
+        )
+    }
+
+    // caches a manifest fetched `age` ago, instead of waiting for it to get stale
+    fn cache_manifest(
+        repository: &ManifestRepository,
+        package_config: &PackageConfig,
+        version: &str,
+        age: Duration,
+    ) {
+        let source: NpmPackageManifest = from_str(&manifest_body(version)).unwrap();
+
+        repository
+            .store
+            .cache
+            .set_with_age(
+                manifest_key(package_config, ManifestDetail::Full),
+                Arc::new(PackageManifest::new(&source, package_config)),
+                age,
+            )
+            .unwrap();
+    }
+
+    // serves manifests of "2.0.0" once `release` is called or dropped, a
+    // caller that waits for the response doesn't see the cached manifest
+    fn serve_after_release() -> (TestServer, mpsc::Sender<()>) {
+        let (release, released) = mpsc::channel::<()>();
+
+        let server = TestServer::start(move |_| {
+            let _ = released.recv_timeout(Duration::from_secs(5));
+            response(200, &[], &manifest_body("2.0.0"))
+        });
+
+        (server, release)
+    }
+
+    // for results of background refreshes, which can't be waited for directly
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_serves_stale_manifests_while_refreshing() {
+        let (server, release) = serve_after_release();
+
+        let cache_directory = create_cache_directory();
+        let repository = create_repository(
+            &cache_directory,
+            Duration::from_secs(15),
+            Duration::from_secs(300),
+        );
+
+        let mut package_config = create_package_config("");
+        package_config.registry = server.url.clone();
+
+        cache_manifest(
+            &repository,
+            &package_config,
+            "1.0.0",
+            Duration::from_secs(20),
+        );
+
+        let versions = || {
+            version_strings(
+                &repository
+                    .get_manifest(&package_config, ManifestDetail::Full)
+                    .unwrap(),
+            )
+        };
+
+        // the refresh is still waiting for the registry, so
+        // neither of these callers waited for it
+        for _ in 0..3 {
+            assert_eq!(versions(), vec!["1.0.0"]);
+        }
+
+        drop(release);
+
+        wait_until(|| versions() == vec!["2.0.0"]);
+        assert_eq!(server.requests(), 1);
+
+        remove_dir_all(cache_directory).unwrap();
+    }
+
+    #[test]
+    fn test_keeps_stale_manifests_if_refresh_fails() {
+        let server = TestServer::start(|_| response(500, &[], ""));
+
+        let cache_directory = create_cache_directory();
+        let repository = create_repository(
+            &cache_directory,
+            Duration::from_secs(15),
+            Duration::from_secs(300),
+        );
+
+        let mut package_config = create_package_config("[retry]\nmax_attempts = 1");
+        package_config.registry = server.url.clone();
+
+        cache_manifest(
+            &repository,
+            &package_config,
+            "1.0.0",
+            Duration::from_secs(20),
+        );
+
+        let get = || {
+            let (manifest, status) = repository
+                .get_manifest_with_status(&package_config, ManifestDetail::Full)
+                .unwrap();
+            (version_strings(&manifest), status)
+        };
+
+        assert_eq!(get(), (vec![String::from("1.0.0")], ManifestStatus::Fresh));
+
+        wait_until(|| get().1 == ManifestStatus::Degraded);
+
+        assert_eq!(
+            get(),
+            (vec![String::from("1.0.0")], ManifestStatus::Degraded)
+        );
+        // the failure is remembered, later calls don't retry it
+        assert_eq!(server.requests(), 1);
+
+        remove_dir_all(cache_directory).unwrap();
+    }
+
+    #[test]
+    fn test_serves_stale_manifests_after_long_ttls() {
+        let (server, release) = serve_after_release();
+
+        let cache_directory = create_cache_directory();
+        // a ttl longer than max_age still leaves a stale window after it
+        let repository = create_repository(
+            &cache_directory,
+            Duration::from_secs(15),
+            Duration::from_secs(10),
+        );
+
+        let mut package_config = create_package_config("");
+        package_config.registry = server.url.clone();
+
+        let versions = || {
+            version_strings(
+                &repository
+                    .get_manifest(&package_config, ManifestDetail::Full)
+                    .unwrap(),
+            )
+        };
+
+        cache_manifest(
+            &repository,
+            &package_config,
+            "1.0.0",
+            Duration::from_secs(20),
+        );
+        assert_eq!(versions(), vec!["1.0.0"]);
+
+        drop(release);
+
+        // past the stale window the refresh is waited for
+        cache_manifest(
+            &repository,
+            &package_config,
+            "1.0.0",
+            Duration::from_secs(30),
+        );
+        assert_eq!(versions(), vec!["2.0.0"]);
+
+        remove_dir_all(cache_directory).unwrap();
+    }
+
+    #[test]
+    fn test_accepts_huge_ttls() {
+        let cache_directory = create_cache_directory();
+        let repository = create_repository(
+            &cache_directory,
+            Duration::from_secs(u64::MAX),
+            Duration::from_secs(u64::MAX),
+        );
+
+        let package_config = create_package_config("abbreviated_manifest = true");
+
+        cache_manifest(
+            &repository,
+            &package_config,
+            "1.0.0",
+            Duration::from_secs(20),
+        );
+
+        let manifest = repository
+            .get_manifest(&package_config, ManifestDetail::Abbreviated)
+            .unwrap();
+
+        assert_eq!(version_strings(&manifest), vec!["1.0.0"]);
+
+        remove_dir_all(cache_directory).unwrap();
+    }
+
+    #[test]
+    fn test_falls_back_to_the_last_good_manifest() {
+        let server = TestServer::start(|_| response(503, &[], ""));
+
+        let cache_directory = create_cache_directory();
+        let repository = create_repository(
+            &cache_directory,
+            Duration::from_secs(15),
+            Duration::from_secs(0),
+        );
+
+        let mut package_config = create_package_config("[retry]\nmax_attempts = 1");
+        package_config.registry = server.url.clone();
+
+        // too old to be served while refreshing, so the failing fetch is awaited
+        cache_manifest(
+            &repository,
+            &package_config,
+            "1.0.0",
+            Duration::from_secs(20),
+        );
+
+        let (manifest, status) = repository
+            .get_manifest_with_status(&package_config, ManifestDetail::Full)
+            .unwrap();
+
+        assert_eq!(version_strings(&manifest), vec!["1.0.0"]);
+        assert_eq!(status, ManifestStatus::Degraded);
+        assert_eq!(server.requests(), 1);
+
+        remove_dir_all(cache_directory).unwrap();
+    }
+
+    #[test]
+    fn test_serves_abbreviated_requests_from_full_manifests() {
+        let server = TestServer::start(|_| response(200, &[], &manifest_body("1.0.0")));
+
+        let cache_directory = create_cache_directory();
+        let repository = create_repository(
+            &cache_directory,
+            Duration::from_secs(15),
+            Duration::from_secs(300),
+        );
+
+        let mut package_config = create_package_config("abbreviated_manifest = true");
+        package_config.registry = server.url.clone();
+
+        let abbreviated_key = manifest_key(&package_config, ManifestDetail::Abbreviated);
+        let get = |detail| repository.get_manifest(&package_config, detail).unwrap();
+
+        get(ManifestDetail::Abbreviated);
+        assert_eq!(server.requests(), 1);
+        assert!(repository.store.path(&abbreviated_key).exists());
+
+        get(ManifestDetail::Full);
+        assert_eq!(server.requests(), 2);
+
+        // the abbreviated copy isn't kept next to the full one
+        assert!(repository
+            .store
+            .cache
+            .get_stale(&abbreviated_key)
+            .unwrap()
+            .is_none());
+        assert!(!repository.store.path(&abbreviated_key).exists());
+
+        get(ManifestDetail::Abbreviated);
+        assert_eq!(server.requests(), 2);
+
+        remove_dir_all(cache_directory).unwrap();
+    }
+
+    #[test]
+    fn test_remembers_fetch_errors() {
+        let server = TestServer::start(|_| response(500, &[], ""));
+
+        let mut package_config = create_package_config("[retry]\nmax_attempts = 1");
+        package_config.registry = server.url.clone();
+
+        let cache_directory = create_cache_directory();
+        let repository = create_repository(
+            &cache_directory,
+            Duration::from_secs(15),
+            Duration::from_secs(300),
+        );
+
+        for _ in 0..3 {
+            assert!(repository
+                .get_manifest(&package_config, ManifestDetail::Full)
+                .is_err());
+        }
+
+        assert_eq!(server.requests(), 1);
+
+        remove_dir_all(cache_directory).unwrap();
+    }
+
+    #[test]
+    fn test_restores_persisted_manifests() {
+        let cache_directory = create_cache_directory();
+
+        let package_config = create_package_config("");
+        let key = manifest_key(&package_config, ManifestDetail::Full);
+        let validators = ManifestValidators {
+            etag: Some(String::from("\"abc\"")),
+            last_modified: None,
+        };
+
+        let source: NpmPackageManifest = from_str(
+            r#"{
+                "versions": { "18.2.0": { "dist": { "tarball": "https://r/react-18.2.0.tgz" } } },
+                "time": { "18.2.0": "2022-06-14T12:00:00.000Z" }
+            }"#,
+        )
+        .unwrap();
+
+        let create_repository = || {
+            ManifestRepository::new(
+                &cache_directory,
+                Arc::new(HttpClients::new(HttpConfig::default())),
+                Duration::from_secs(15),
+                Duration::from_secs(300),
+                Duration::from_secs(10),
+                &CacheLimits::default(),
+                None,
+            )
+        };
+
+        create_repository()
+            .store
+            .persist(&key, source, validators.clone())
+            .unwrap();
+
+        let repository = create_repository();
+        repository.restore(&[package_config]);
+
+        let entry = repository.store.cache.get_stale(&key).unwrap().unwrap();
+
+        assert_eq!(version_strings(&entry.value), vec!["18.2.0"]);
+        assert_eq!(entry.value.validators, validators);
+        assert!(entry.value.versions[0].published.is_some());
+
+        remove_dir_all(cache_directory).unwrap();
+    }
 }
@@ -0,0 +1,233 @@
+use crate::error::NpmPackageServerError;
+use regex::{Captures, Regex};
+use std::{collections::HashMap, env, fs::read_to_string, path::Path};
+use url::Url;
+
+/// Credentials an `.npmrc` sets for a registry.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NpmrcCredentials {
+    pub token: Option<String>,
+    /// Base64 of "username:password".
+    pub auth: Option<String>,
+    pub username: Option<String>,
+    /// Decoded, the file stores it in base64.
+    pub password: Option<String>,
+    /// "always-auth", for the registry or globally.
+    pub always_auth: bool,
+}
+
+/// The settings of an `.npmrc` file used to find
+/// the registry of a package and its credentials.
+pub struct Npmrc {
+    values: HashMap<String, String>,
+}
+
+fn invalid(message: String) -> NpmPackageServerError {
+    NpmPackageServerError::InvalidConfig(message)
+}
+
+impl Npmrc {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Npmrc, NpmPackageServerError> {
+        Npmrc::parse(&read_to_string(path)?, |name| env::var(name).ok())
+    }
+
+    /// Parses "key=value" lines, replacing `${NAME}` in values with
+    /// `get_variable(NAME)` like npm does. Unset variables are an error.
+    pub fn parse<F: Fn(&str) -> Option<String>>(
+        source: &str,
+        get_variable: F,
+    ) -> Result<Npmrc, NpmPackageServerError> {
+        let variable_regex = Regex::new(r"\$\{([^}]+)\}").unwrap();
+        let mut values = HashMap::new();
+
+        for (index, line) in source.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("line {} of .npmrc isn't key=value", index + 1)))?;
+
+            let value = value.trim().trim_matches('"');
+            let mut missing: Option<String> = None;
+
+            let value = variable_regex.replace_all(value, |captures: &Captures| {
+                get_variable(&captures[1]).unwrap_or_else(|| {
+                    missing = Some(captures[1].to_string());
+                    String::new()
+                })
+            });
+
+            if let Some(name) = missing {
+                return Err(invalid(format!(
+                    "environment variable {} used on line {} of .npmrc isn't set",
+                    name,
+                    index + 1
+                )));
+            }
+
+            values.insert(key.trim().to_string(), value.into_owned());
+        }
+
+        Ok(Npmrc { values })
+    }
+
+    /// The registry of the package's scope, or the default one.
+    pub fn registry_for(&self, package_name: &str) -> Option<String> {
+        let scoped = if package_name.starts_with('@') {
+            package_name
+                .split('/')
+                .next()
+                .and_then(|scope| self.values.get(&format!("{}:registry", scope)))
+        } else {
+            None
+        };
+
+        let mut registry = scoped.or_else(|| self.values.get("registry"))?.clone();
+
+        // registries in .npmrc usually don't have the trailing slash
+        if !registry.ends_with('/') {
+            registry.push('/');
+        }
+
+        Some(registry)
+    }
+
+    /// Credentials are keyed by the registry url without the protocol, e.g.
+    /// "//npm.example.com/repository/npm/:_authToken". Like npm, the closest
+    /// parent path with credentials is used if the url itself has none.
+    pub fn credentials_for(
+        &self,
+        registry: &str,
+    ) -> Result<NpmrcCredentials, NpmPackageServerError> {
+        let url = Url::parse(registry)
+            .map_err(|error| invalid(format!("invalid registry {}: {}", registry, error)))?;
+
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Ok(NpmrcCredentials::default()),
+        };
+
+        let mut path = url.path().trim_end_matches('/').to_string();
+
+        loop {
+            let prefix = format!("//{}{}/:", host, path);
+            let get = |key: &str| self.values.get(&format!("{}{}", prefix, key)).cloned();
+
+            let password = match get("_password") {
+                Some(password) => Some(decode_password(&password)?),
+                None => None,
+            };
+
+            let always_auth =
+                get("always-auth").or_else(|| self.values.get("always-auth").cloned());
+
+            let credentials = NpmrcCredentials {
+                token: get("_authToken"),
+                auth: get("_auth"),
+                username: get("username"),
+                password,
+                always_auth: matches!(always_auth.as_deref(), Some("true")),
+            };
+
+            let has_credentials = credentials.token.is_some()
+                || credentials.auth.is_some()
+                || credentials.username.is_some();
+
+            if has_credentials || path.is_empty() {
+                return Ok(credentials);
+            }
+
+            path.truncate(path.rfind('/').unwrap_or(0));
+        }
+    }
+}
+
+fn decode_password(encoded: &str) -> Result<String, NpmPackageServerError> {
+    base64::decode(encoded)
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or_else(|| invalid(String::from("_password in .npmrc isn't valid base64")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Npmrc {
+        Npmrc::parse(source, |name| match name {
+            "NPM_TOKEN" => Some(String::from("secret")),
+            _ => None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_finds_registries_by_scope() {
+        let npmrc = parse(
+            "# company registries\n\
+             registry=https://npm.example.com/repository/npm\n\
+             @internal:registry=https://nexus.example.com/repository/internal/\n",
+        );
+
+        assert_eq!(
+            npmrc.registry_for("react").unwrap(),
+            "https://npm.example.com/repository/npm/"
+        );
+        assert_eq!(
+            npmrc.registry_for("@internal/ui").unwrap(),
+            "https://nexus.example.com/repository/internal/"
+        );
+        assert_eq!(
+            npmrc.registry_for("@other/ui").unwrap(),
+            "https://npm.example.com/repository/npm/"
+        );
+        assert!(parse("").registry_for("react").is_none());
+    }
+
+    #[test]
+    fn test_finds_credentials_by_registry() {
+        let npmrc = parse(
+            "//npm.example.com/repository/npm/:_authToken=${NPM_TOKEN}\n\
+             //nexus.example.com:8081/:username=ci\n\
+             //nexus.example.com:8081/:_password=\"aHVudGVyMg==\"\n\
+             //nexus.example.com:8081/:always-auth=true\n",
+        );
+
+        assert_eq!(
+            npmrc
+                .credentials_for("https://npm.example.com/repository/npm/")
+                .unwrap()
+                .token,
+            Some(String::from("secret"))
+        );
+
+        assert_eq!(
+            npmrc
+                .credentials_for("http://nexus.example.com:8081/repository/internal/")
+                .unwrap(),
+            NpmrcCredentials {
+                username: Some(String::from("ci")),
+                password: Some(String::from("hunter2")),
+                always_auth: true,
+                ..NpmrcCredentials::default()
+            }
+        );
+
+        assert_eq!(
+            npmrc
+                .credentials_for("https://registry.npmjs.org/")
+                .unwrap(),
+            NpmrcCredentials::default()
+        );
+    }
+
+    #[test]
+    fn test_rejects_unset_variables() {
+        assert!(Npmrc::parse("//r/:_authToken=${MISSING}", |_| None).is_err());
+    }
+}
@@ -9,6 +9,8 @@ pub enum PackageFileRequestParsingError {
     InvalidNameFormat(String),
     #[error("missing version: {0}")]
     MissingVersion(String),
+    #[error("unsafe path: {0}")]
+    UnsafePath(String),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -18,6 +20,28 @@ pub struct PackageFileRequest {
     pub path: String,
 }
 
+fn has_percent_encoded_octet(segment: &str) -> bool {
+    segment.as_bytes().windows(3).any(|window| {
+        window[0] == b'%' && window[1].is_ascii_hexdigit() && window[2].is_ascii_hexdigit()
+    })
+}
+
+// the url is percent-decoded by rouille before it gets here,
+// so anything that still looks encoded was encoded twice
+fn validate_segment(segment: &str, source: &str) -> Result<(), PackageFileRequestParsingError> {
+    if segment == ".."
+        || segment.contains('\\')
+        || segment.contains('\0')
+        || has_percent_encoded_octet(segment)
+    {
+        return Err(PackageFileRequestParsingError::UnsafePath(
+            source.to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
 impl FromStr for PackageFileRequest {
     type Err = PackageFileRequestParsingError;
 
@@ -31,18 +55,20 @@ impl FromStr for PackageFileRequest {
         let mut parts = s.split("/");
 
         match parts.next() {
-            Some(s) => {
-                result.name.push_str(s);
+            Some(name) => {
+                validate_segment(name, s)?;
+                result.name.push_str(name);
 
-                if s.starts_with("@") {
+                if name.starts_with('@') {
                     match parts.next() {
-                        Some(s) => {
+                        Some(name) => {
+                            validate_segment(name, s)?;
                             result.name.push('/');
-                            result.name.push_str(s);
+                            result.name.push_str(name);
                         }
                         None => {
                             return Err(PackageFileRequestParsingError::InvalidScopedName(
-                                s.to_string(),
+                                name.to_string(),
                             ));
                         }
                     }
@@ -59,12 +85,17 @@ impl FromStr for PackageFileRequest {
 
         match version {
             Some(version) => {
-                if version == "" {
+                if version.is_empty() {
                     return Err(PackageFileRequestParsingError::MissingVersion(
                         s.to_string(),
                     ));
                 }
 
+                if version == "." {
+                    return Err(PackageFileRequestParsingError::UnsafePath(s.to_string()));
+                }
+
+                validate_segment(version, s)?;
                 result.version.push_str(version);
             }
             None => {
@@ -74,13 +105,28 @@ impl FromStr for PackageFileRequest {
             }
         }
 
-        if let Some(part) = parts.next() {
-            result.path.push_str(part);
+        let parts: Vec<&str> = parts.collect();
+
+        // "react/0.1.0//etc/passwd" would turn into an absolute path
+        if parts.len() > 1 && parts[0].is_empty() {
+            return Err(PackageFileRequestParsingError::UnsafePath(s.to_string()));
         }
 
-        for part in parts {
+        let mut segments: Vec<&str> = vec![];
+
+        for part in parts.iter() {
+            validate_segment(part, s)?;
+
+            if !part.is_empty() && *part != "." {
+                segments.push(part);
+            }
+        }
+
+        result.path.push_str(&segments.join("/"));
+
+        // a trailing slash denotes a directory
+        if !segments.is_empty() && matches!(parts.last(), Some(&"") | Some(&".")) {
             result.path.push('/');
-            result.path.push_str(part);
         }
 
         Ok(result)
@@ -150,7 +196,7 @@ mod tests {
     #[test]
     fn test_regular_name_invalid() {
         let result = "react".parse::<PackageFileRequest>();
-        assert_eq!(result.is_err(), true);
+        assert!(result.is_err());
     }
 
     #[test]
@@ -171,4 +217,47 @@ mod tests {
         let result = "react/".parse::<PackageFileRequest>();
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_normalizes_path() {
+        let result = "react/0.1.0/./lib//components/./".parse::<PackageFileRequest>();
+
+        assert_eq!(
+            result.unwrap(),
+            PackageFileRequest {
+                name: "react".into(),
+                version: "0.1.0".into(),
+                path: "lib/components/".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_rejects_path_traversal() {
+        let attacks = [
+            "react/0.1.0/../../../etc/passwd",
+            "react/0.1.0/lib/../../package.json",
+            "react/0.1.0/..",
+            "react/0.1.0//etc/passwd",
+            "react/0.1.0/..\\..\\etc\\passwd",
+            "react/0.1.0/%2e%2e/%2e%2e/etc/passwd",
+            "react/0.1.0/%2E%2E%2Fetc%2Fpasswd",
+            "react/0.1.0/lib%5c..%5cpackage.json",
+            "react/0.1.0/README.md\0.js",
+            "react/../0.1.0/README.md",
+            "react/./README.md",
+            "react/0.1.0%2f..%2f..%2f/README.md",
+            "@scope/../0.1.0/README.md",
+        ];
+
+        for attack in attacks.iter() {
+            let result = attack.parse::<PackageFileRequest>();
+
+            assert!(
+                matches!(result, Err(PackageFileRequestParsingError::UnsafePath(_))),
+                "{} must be rejected",
+                attack
+            );
+        }
+    }
 }
@@ -1,12 +1,13 @@
 use crate::{
     common::AppData,
-    error::{NpmPackageServerError, PackageTrackingError},
+    error::{ManifestFetchError, NpmPackageServerError, PackageTrackingError},
+    npm_registry::ManifestDetail,
 };
 use badgen::{badge, Color, Style};
 use rouille::{Response, ResponseBody};
 use std::sync::Arc;
 
-const FATAL_ERROR_BADGE: &'static str = r###"
+const FATAL_ERROR_BADGE: &str = r###"
 <svg
   width="159.6"
   height="20"
@@ -66,8 +67,7 @@ fn render_badge(
     let mut style = Style::classic();
     style.background = color;
 
-    badge(&style, description, Some(title))
-        .map_err(|error| NpmPackageServerError::BadgeRendering(error))
+    badge(&style, description, Some(title)).map_err(NpmPackageServerError::BadgeRendering)
 }
 
 fn badge_handler_inner(
@@ -82,22 +82,32 @@ fn badge_handler_inner(
         PackageTrackingError::PackageIsNotTracked(package_name.to_string()),
     )?;
 
-    let manifest = app_data.manifest_repository.get_manifest(package_config)?;
+    let manifest = app_data
+        .manifest_repository
+        .get_manifest(package_config, ManifestDetail::Abbreviated)?;
 
+    // prereleases are only shown when there is no stable version yet
     let version = manifest
-        .versions
-        .get(0)
+        .latest_stable()
+        .or_else(|| manifest.versions.first())
         .ok_or(PackageTrackingError::NoVersions(package_name.to_string()))?;
 
     let label = version.version.to_string();
 
-    let body = render_badge("npm", &label, Color::Green)?;
+    let body = if version.is_prerelease() {
+        render_badge("npm", &label, Color::Orange)?
+    } else {
+        render_badge("npm", &label, Color::Green)?
+    };
 
     Ok(Response {
         status_code: 200,
         headers: vec![
             ("Content-Type".into(), "image/svg+xml; charset=utf-8".into()),
-            ("cache-control".into(), "public, max-age=900".into()),
+            (
+                "cache-control".into(),
+                format!("public, max-age={}", app_data.config.badge_max_age).into(),
+            ),
         ],
         data: ResponseBody::from_string(body),
         upgrade: None,
@@ -113,15 +123,25 @@ pub fn badge_handler(
     match result {
         Ok(response) => Ok(response),
         Err(error) => Ok(match error {
+            NpmPackageServerError::ManifestFetchError(ref fetch_error) => {
+                let description = match fetch_error {
+                    ManifestFetchError::PackageDoesNotExistError { .. } => "package not found",
+                    ManifestFetchError::Unauthorized { .. } => "registry unauthorized",
+                    ManifestFetchError::Forbidden { .. } => "registry forbidden",
+                    ManifestFetchError::RateLimited { .. } => "registry rate limited",
+                    ManifestFetchError::Unavailable { .. } => "registry unavailable",
+                    ManifestFetchError::Timeout(_) => "registry timed out",
+                    _ => "couldn't fetch manifest",
+                };
+
+                error_badge_response(description, error.status_code())
+            }
             NpmPackageServerError::PackageNameIsNotSpecified => {
                 error_badge_response("bad request", 400)
             }
             NpmPackageServerError::BadgeRendering(_) => {
                 error_badge_response("failed to render badge", 500)
             }
-            NpmPackageServerError::ManifestFetchError(_) => {
-                error_badge_response("couldn't fetch manifest", 500)
-            }
             NpmPackageServerError::PackageTrackingError(_) => {
                 error_badge_response("package is not tracked or there are no versions", 404)
             }
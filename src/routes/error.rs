@@ -0,0 +1,30 @@
+use crate::{common::AppData, error::NpmPackageServerError};
+use rouille::Response;
+
+pub fn error_response(app_data: &AppData, error: &NpmPackageServerError, json: bool) -> Response {
+    let status = error.status_code();
+    let message = error.public_message();
+
+    if json {
+        return Response::json(&json!({
+            "status": status,
+            "error": message,
+        }))
+        .with_status_code(status);
+    }
+
+    let data = json!({
+        "labels": app_data.config.labels,
+        "status": status,
+        "message": message,
+        "banner_gradient_left_color": app_data.config.banner_gradient_left_color,
+        "banner_gradient_right_color": app_data.config.banner_gradient_right_color,
+        "banner_color": app_data.config.banner_color
+    });
+
+    match app_data.handlebars.render("error", &data) {
+        Ok(body) => Response::html(body).with_status_code(status),
+        // the error page itself is broken, plain text is all that's left
+        Err(_) => Response::text(message).with_status_code(status),
+    }
+}
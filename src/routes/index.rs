@@ -1,9 +1,9 @@
-use crate::config::Config;
-use crate::error::PackageTrackingError;
-use crate::npm_registry::PackageManifest;
+use crate::config::{Config, Labels};
+use crate::error::{ManifestFetchError, PackageTrackingError};
+use crate::npm_registry::{ManifestDetail, ManifestStatus, PackageManifest};
 use crate::{common::AppData, error::NpmPackageServerError};
 use chrono::Utc;
-use log::debug;
+use log::{debug, warn};
 use rouille::Response;
 use serde_derive::Serialize;
 use std::ops::Sub;
@@ -15,6 +15,7 @@ struct TemplateVersion {
     version: String,
     time: String,
     formatted_time: String,
+    prerelease: bool,
 }
 
 #[derive(Serialize)]
@@ -22,6 +23,9 @@ struct TemplatePackage {
     name: String,
     clear_name: String,
     versions: Vec<TemplateVersion>,
+    degraded: bool,
+    // why there are no versions
+    error: Option<String>,
 }
 
 fn get_language_by_iso639_1_code(iso639_1: &str) -> Box<dyn Language + Send + Sync + 'static> {
@@ -31,6 +35,24 @@ fn get_language_by_iso639_1_code(iso639_1: &str) -> Box<dyn Language + Send + Sy
     }
 }
 
+fn error_label<'a>(labels: &'a Labels, error: &NpmPackageServerError) -> &'a str {
+    let error = match error {
+        NpmPackageServerError::ManifestFetchError(error) => error,
+        _ => return &labels.registry_error,
+    };
+
+    match error {
+        ManifestFetchError::PackageDoesNotExistError { .. } => &labels.not_found,
+        ManifestFetchError::Unauthorized { .. } => &labels.unauthorized,
+        ManifestFetchError::Forbidden { .. } => &labels.forbidden,
+        ManifestFetchError::RateLimited { .. } => &labels.rate_limited,
+        ManifestFetchError::Unavailable { .. } | ManifestFetchError::Timeout(_) => {
+            &labels.unavailable
+        }
+        _ => &labels.registry_error,
+    }
+}
+
 fn transform_version_info_for_templates(
     config: &Config,
     source: Arc<PackageManifest>,
@@ -49,17 +71,20 @@ fn transform_version_info_for_templates(
         .versions
         .iter()
         .map(|version| {
-            let published = version.published.to_rfc3339();
+            let published = version
+                .published
+                .map_or(String::new(), |published| published.to_rfc3339());
 
-            let published_ago = now
-                .sub(version.published)
-                .to_std()
+            let published_ago = version
+                .published
+                .and_then(|published| now.sub(published).to_std().ok())
                 .map_or(String::from("unknown"), |d| formatter.convert(d));
 
             TemplateVersion {
                 version: version.version.to_string(),
                 time: published.clone(),
                 formatted_time: published_ago,
+                prerelease: version.is_prerelease(),
             }
         })
         .collect()
@@ -83,22 +108,41 @@ pub fn index_handler(
         .config
         .packages
         .iter()
-        .filter_map(|package_config| {
-            let manifest = app_data
-                .manifest_repository
-                .get_manifest(package_config)
-                .ok()?;
+        .map(|package_config| {
+            let name = package_config.get_public_name().clone();
+            let clear_name = package_config.identifier_safe_key();
 
-            let name = package_config.get_public_name();
-
-            Some(TemplatePackage {
-                name: name.clone(),
-                clear_name: package_config.identifier_safe_key(),
+            let (manifest, status) = match app_data
+                .manifest_repository
+                .get_manifest_with_status(package_config, ManifestDetail::Full)
+            {
+                Ok(result) => result,
+                Err(error) => {
+                    warn!(
+                        "no manifest of \"{}\" to show: {}",
+                        package_config.name, error
+                    );
+
+                    return TemplatePackage {
+                        name,
+                        clear_name,
+                        versions: vec![],
+                        degraded: false,
+                        error: Some(error_label(&app_data.config.labels, &error).to_string()),
+                    };
+                }
+            };
+
+            TemplatePackage {
+                name,
+                clear_name,
                 versions: transform_version_info_for_templates(
                     &app_data.config,
                     Arc::clone(&manifest),
                 ),
-            })
+                degraded: status == ManifestStatus::Degraded,
+                error: None,
+            }
         })
         .collect::<Vec<TemplatePackage>>();
 
@@ -1,6 +1,10 @@
 use std::sync::Arc;
 
-use crate::{common::AppData, error::NpmPackageServerError};
+use crate::{
+    common::AppData,
+    error::NpmPackageServerError,
+    npm_registry::{ManifestDetail, ManifestStatus},
+};
 use rouille::{Response, ResponseBody};
 use serde::Serialize;
 
@@ -12,6 +16,8 @@ pub fn list_versions_handler(
     struct VersionsListItem {
         name: String,
         versions: Vec<String>,
+        prereleases: Vec<String>,
+        degraded: bool,
     }
 
     let result: Vec<VersionsListItem> = app_data
@@ -19,18 +25,27 @@ pub fn list_versions_handler(
         .packages
         .iter()
         .filter_map(|package_config| {
-            let manifest = app_data
+            let (manifest, status) = app_data
                 .manifest_repository
-                .get_manifest(package_config)
+                .get_manifest_with_status(package_config, ManifestDetail::Abbreviated)
                 .ok()?;
 
+            let (prereleases, versions): (Vec<_>, Vec<_>) = manifest
+                .versions
+                .iter()
+                .partition(|version| version.is_prerelease());
+
             Some(VersionsListItem {
                 name: package_config.get_public_name().clone(),
-                versions: manifest
-                    .versions
+                versions: versions
+                    .iter()
+                    .map(|version| version.version.to_string())
+                    .collect(),
+                prereleases: prereleases
                     .iter()
                     .map(|version| version.version.to_string())
                     .collect(),
+                degraded: status == ManifestStatus::Degraded,
             })
         })
         .collect();
@@ -0,0 +1,11 @@
+use crate::{common::AppData, error::NpmPackageServerError};
+use rouille::Response;
+use std::sync::Arc;
+
+pub fn metrics_handler(app_data: Arc<AppData>) -> Result<Response, NpmPackageServerError> {
+    Ok(Response::json(&json!({
+        "manifest_cache": app_data.manifest_repository.cache_stats()?,
+        "index_cache": app_data.download_manager.index_cache_stats()?,
+        "http_clients": app_data.http_clients.client_count(),
+    })))
+}
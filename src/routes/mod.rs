@@ -1,9 +1,13 @@
 mod badge;
+mod error;
 mod index;
 mod list_versions;
+mod metrics;
 mod show;
 
 pub use badge::badge_handler;
+pub use error::error_response;
 pub use index::index_handler;
 pub use list_versions::list_versions_handler;
+pub use metrics::metrics_handler;
 pub use show::show_handler;
@@ -1,30 +1,54 @@
+use crate::common::{get_mime_type, show_url};
 use crate::error::PackageTrackingError;
-use crate::npm_registry::DownloadManager;
+use crate::listing::read_meta;
+use crate::npm_registry::ManifestDetail;
 use crate::request::PackageFileRequest;
+use crate::storage::{EntryKind, PackageFiles};
 use crate::{common::AppData, error::NpmPackageServerError};
 use log::debug;
-use rouille::{extension_to_mime, Response};
+use rouille::Response;
+use semver::Version;
 use std::sync::Arc;
-use std::{ffi::OsStr, fs::File, path::Path};
 
-fn get_extension_from_filename(filename: Option<&str>) -> Option<&str> {
-    filename
-        .map(|filename| Path::new(filename).extension().and_then(OsStr::to_str))
-        .flatten()
+// ranges and dist-tags may start resolving to a different version
+// at any moment, so redirects to concrete versions are short-lived
+const VERSION_REDIRECT_MAX_AGE: u64 = 60;
+
+fn directory_listing_response(
+    app_data: &AppData,
+    request: &PackageFileRequest,
+    files: &dyn PackageFiles,
+) -> Result<Response, NpmPackageServerError> {
+    let entries = files.read_directory(&request.path)?;
+
+    let data = json!({
+        "labels": app_data.config.labels,
+        "name": request.name,
+        "version": request.version,
+        "path": format!("/{}", request.path),
+        "has_parent": !request.path.is_empty(),
+        "entries": entries,
+        "banner_gradient_left_color": app_data.config.banner_gradient_left_color,
+        "banner_gradient_right_color": app_data.config.banner_gradient_right_color,
+        "banner_color": app_data.config.banner_color
+    });
+
+    let body = app_data.handlebars.render("directory", &data)?;
+
+    Ok(Response::html(body))
 }
 
 pub fn show_handler(
     app_data: Arc<AppData>,
     path: String,
+    meta: bool,
 ) -> Result<Response, NpmPackageServerError> {
     let request: PackageFileRequest = path.parse()?;
 
     debug!("Client requested: {}", path);
 
-    if request.path == "" && !path.ends_with("/") {
-        let mut target = String::from("/show/");
-        target.push_str(&path);
-        target.push_str("/");
+    if request.path.is_empty() && !path.ends_with('/') && !meta {
+        let target = show_url(&request.name, &request.version, "");
 
         return Ok(Response::redirect_301(target));
     }
@@ -34,53 +58,88 @@ pub fn show_handler(
         .get_package(&request.name)
         .ok_or_else(|| PackageTrackingError::PackageIsNotTracked(request.name.clone()))?;
 
-    let download_paths = DownloadManager::get_download_paths(package_config, &request);
-
-    if !download_paths.package_directory.exists() {
-        let info = app_data.manifest_repository.get_manifest(package_config)?;
-        let tarball_url = info.get_tarball_url(&request.version).ok_or_else(|| {
-            NpmPackageServerError::Registry(format!(
-                "the specified version \"{}\" does not exist in \"{}\"",
-                &request.version, info.registry_url
-            ))
+    if Version::parse(&request.version).is_err() {
+        let info = app_data
+            .manifest_repository
+            .get_manifest(package_config, ManifestDetail::Abbreviated)?;
+        let resolved = info.resolve_version(&request.version).ok_or_else(|| {
+            NpmPackageServerError::NoSuchVersion {
+                registry: info.registry_url.clone(),
+                name: request.name.clone(),
+                version: request.version.clone(),
+            }
         })?;
 
-        app_data.download_manager.download(
-            package_config,
-            tarball_url,
-            download_paths.root_directory,
-        )?;
-    }
+        let mut target = show_url(&request.name, &resolved.version.to_string(), &request.path);
+
+        if meta {
+            target.push_str("?meta");
+        }
 
-    if !download_paths.requested_file_path.exists() {
-        return Err(NpmPackageServerError::NoSuchFile(request.path));
+        return Ok(Response::redirect_302(target).with_public_cache(VERSION_REDIRECT_MAX_AGE));
     }
 
-    let str = download_paths
-        .requested_file_path
-        .to_str()
-        .ok_or_else(|| NpmPackageServerError::Generic(String::from("failed to convert path")))?
-        .to_owned();
+    let download_paths = app_data
+        .download_manager
+        .get_download_paths(package_config, &request);
+
+    // the lease must outlive every access to the version directory
+    let _lease = app_data.download_manager.lease(&download_paths);
+
+    if !download_paths.is_unpacked() {
+        let info = app_data
+            .manifest_repository
+            .get_manifest(package_config, ManifestDetail::Abbreviated)?;
+        let version = info.get_version(&request.version).ok_or_else(|| {
+            NpmPackageServerError::NoSuchVersion {
+                registry: info.registry_url.clone(),
+                name: request.name.clone(),
+                version: request.version.clone(),
+            }
+        })?;
 
-    let extension = get_extension_from_filename(Some(&str));
+        app_data
+            .download_manager
+            .download(package_config, version, &download_paths)?;
+    }
 
-    let mime = extension
-        .map(|extension| {
-            if extension == "md" {
-                return "text/markdown; charset=utf-8";
-            }
+    let files = app_data.download_manager.open(&download_paths)?;
 
-            return extension_to_mime(extension);
-        })
-        .unwrap_or("text/html");
+    if meta {
+        return Ok(Response::json(&read_meta(files.as_ref(), &request.path)?));
+    }
 
-    let used_mime = if mime == "application/octet-stream" {
-        "text/html"
+    let requested_file = if request.path.is_empty() {
+        match files.entry_kind(&package_config.index_file)? {
+            Some(EntryKind::File { .. }) => &package_config.index_file,
+            _ => return directory_listing_response(&app_data, &request, files.as_ref()),
+        }
     } else {
-        mime
+        match files.entry_kind(&request.path)? {
+            Some(EntryKind::File { .. }) => &request.path,
+            Some(EntryKind::Directory) => {
+                if !path.ends_with('/') {
+                    let target = show_url(
+                        &request.name,
+                        &request.version,
+                        &format!("{}/", request.path),
+                    );
+
+                    return Ok(Response::redirect_301(target));
+                }
+
+                return directory_listing_response(&app_data, &request, files.as_ref());
+            }
+            None => return Err(NpmPackageServerError::NoSuchFile(request.path)),
+        }
     };
 
-    let file = File::open(download_paths.requested_file_path)?;
+    let mime = get_mime_type(requested_file);
 
-    Ok(Response::from_file(used_mime, file))
+    Ok(Response {
+        status_code: 200,
+        headers: vec![("Content-Type".into(), mime.into())],
+        data: files.open(requested_file)?,
+        upgrade: None,
+    })
 }
@@ -0,0 +1,189 @@
+use crate::error::NpmPackageServerError;
+use crate::listing::{read_directory, sort_entries, DirectoryEntry};
+use crate::tarball::TarballIndex;
+use rouille::ResponseBody;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File { size: u64 },
+    Directory,
+}
+
+/// Read-only access to the files of a downloaded package version.
+/// Paths are relative to the package root, `""` being the root itself.
+pub trait PackageFiles {
+    fn entry_kind(&self, path: &str) -> Result<Option<EntryKind>, NpmPackageServerError>;
+    fn read_directory(&self, path: &str) -> Result<Vec<DirectoryEntry>, NpmPackageServerError>;
+    fn open(&self, path: &str) -> Result<ResponseBody, NpmPackageServerError>;
+}
+
+/// A package unpacked into a directory.
+pub struct UnpackedPackage {
+    package_directory: PathBuf,
+}
+
+/// A package kept as an uncompressed tar archive with an offset index.
+pub struct TarballPackage {
+    tarball_path: PathBuf,
+    root: String,
+    index: Arc<TarballIndex>,
+}
+
+impl UnpackedPackage {
+    pub fn new(package_directory: PathBuf) -> Self {
+        UnpackedPackage { package_directory }
+    }
+
+    /// Resolves symlinks and `..` in `path` and makes sure
+    /// the result is still located inside the package directory.
+    /// Like in tarball mode, links to directories are skipped.
+    fn resolve(&self, path: &str) -> Result<Option<PathBuf>, NpmPackageServerError> {
+        let full_path = self.package_directory.join(path);
+
+        if !full_path.exists() {
+            return Ok(None);
+        }
+
+        let mut partial_path = self.package_directory.clone();
+
+        for component in Path::new(path).components() {
+            partial_path.push(component);
+
+            if is_link_to_directory(&partial_path) {
+                return Ok(None);
+            }
+        }
+
+        let package_directory = self.package_directory.canonicalize()?;
+        let canonical_path = full_path.canonicalize()?;
+
+        if !canonical_path.starts_with(&package_directory) {
+            return Err(NpmPackageServerError::PathTraversal(
+                full_path.to_string_lossy().into_owned(),
+            ));
+        }
+
+        Ok(Some(canonical_path))
+    }
+
+    fn resolve_existing(&self, path: &str) -> Result<PathBuf, NpmPackageServerError> {
+        self.resolve(path)?
+            .ok_or_else(|| NpmPackageServerError::NoSuchFile(path.to_string()))
+    }
+}
+
+fn is_link_to_directory(path: &Path) -> bool {
+    let is_link =
+        matches!(path.symlink_metadata(), Ok(metadata) if metadata.file_type().is_symlink());
+
+    is_link && path.is_dir()
+}
+
+impl PackageFiles for UnpackedPackage {
+    fn entry_kind(&self, path: &str) -> Result<Option<EntryKind>, NpmPackageServerError> {
+        let resolved_path = match self.resolve(path)? {
+            Some(resolved_path) => resolved_path,
+            None => return Ok(None),
+        };
+
+        let metadata = resolved_path.metadata()?;
+
+        if metadata.is_dir() {
+            Ok(Some(EntryKind::Directory))
+        } else {
+            Ok(Some(EntryKind::File {
+                size: metadata.len(),
+            }))
+        }
+    }
+
+    fn read_directory(&self, path: &str) -> Result<Vec<DirectoryEntry>, NpmPackageServerError> {
+        Ok(read_directory(self.resolve_existing(path)?)?)
+    }
+
+    fn open(&self, path: &str) -> Result<ResponseBody, NpmPackageServerError> {
+        Ok(ResponseBody::from_file(File::open(
+            self.resolve_existing(path)?,
+        )?))
+    }
+}
+
+impl TarballPackage {
+    /// `root` is the directory of the archive the package is stored in, e.g. `package`.
+    pub fn new<P: AsRef<Path>>(tarball_path: P, root: &str, index: Arc<TarballIndex>) -> Self {
+        TarballPackage {
+            tarball_path: tarball_path.as_ref().to_path_buf(),
+            root: root.to_string(),
+            index,
+        }
+    }
+
+    fn key(&self, path: &str) -> String {
+        let path = path.trim_matches('/');
+
+        if path.is_empty() {
+            self.root.clone()
+        } else {
+            format!("{}/{}", self.root, path)
+        }
+    }
+}
+
+impl PackageFiles for TarballPackage {
+    fn entry_kind(&self, path: &str) -> Result<Option<EntryKind>, NpmPackageServerError> {
+        let key = self.key(path);
+
+        if let Some(entry) = self.index.get_file(&key) {
+            return Ok(Some(EntryKind::File { size: entry.size }));
+        }
+
+        if self.index.is_directory(&key) {
+            return Ok(Some(EntryKind::Directory));
+        }
+
+        Ok(None)
+    }
+
+    fn read_directory(&self, path: &str) -> Result<Vec<DirectoryEntry>, NpmPackageServerError> {
+        let key = self.key(path);
+
+        if !self.index.is_directory(&key) {
+            return Err(NpmPackageServerError::NoSuchFile(path.to_string()));
+        }
+
+        let mut entries: Vec<DirectoryEntry> = self
+            .index
+            .read_directory(&key)
+            .into_iter()
+            .map(|(name, entry)| match entry {
+                Some(entry) => DirectoryEntry::new(name, false, entry.size),
+                None => DirectoryEntry::new(name, true, 0),
+            })
+            .collect();
+
+        sort_entries(&mut entries);
+
+        Ok(entries)
+    }
+
+    fn open(&self, path: &str) -> Result<ResponseBody, NpmPackageServerError> {
+        let entry = self
+            .index
+            .get_file(&self.key(path))
+            .ok_or_else(|| NpmPackageServerError::NoSuchFile(path.to_string()))?;
+
+        let mut file = File::open(&self.tarball_path)?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+
+        Ok(ResponseBody::from_reader_and_size(
+            file.take(entry.size),
+            entry.size as usize,
+        ))
+    }
+}
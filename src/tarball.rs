@@ -0,0 +1,594 @@
+use crate::error::TarballDownloadError;
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Read, Write},
+    mem::size_of,
+    path::{Component, Path, PathBuf},
+};
+use tar::{Archive, Entry, EntryType, Header};
+
+// real PAX headers and GNU long names hold a few paths and attributes
+const MAX_EXTENDED_HEADER_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractionLimits {
+    pub max_unpacked_size: u64,
+    pub max_file_count: u64,
+}
+
+/// Location of a file's contents inside of an uncompressed tar archive.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Lets single files be served from an uncompressed tar archive
+/// without unpacking it. Paths are relative to the archive root
+/// and never have leading or trailing slashes.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct TarballIndex {
+    files: BTreeMap<String, IndexEntry>,
+    directories: BTreeSet<String>,
+}
+
+enum ValidatedEntry {
+    File(PathBuf),
+    Directory(PathBuf),
+    Link { path: PathBuf, target: PathBuf },
+}
+
+// writes everything read from `reader` to `writer`
+struct TeeReader<'a, R: Read, W: Write> {
+    reader: R,
+    writer: &'a mut W,
+}
+
+struct EntryValidator<'a> {
+    limits: &'a ExtractionLimits,
+    file_count: u64,
+    unpacked_size: u64,
+}
+
+fn unsafe_archive(message: String) -> TarballDownloadError {
+    TarballDownloadError::UnsafeArchive(message)
+}
+
+/// Lexically normalizes a path relative to the archive root,
+/// returns `None` if it is absolute or escapes the root.
+fn normalize_inside_root(path: &Path) -> Option<PathBuf> {
+    let mut result = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => result.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !result.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(result)
+}
+
+fn path_to_key(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+impl<R: Read, W: Write> Read for TeeReader<'_, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..read])?;
+        Ok(read)
+    }
+}
+
+impl<'a> EntryValidator<'a> {
+    fn new(limits: &'a ExtractionLimits) -> Self {
+        EntryValidator {
+            limits,
+            file_count: 0,
+            unpacked_size: 0,
+        }
+    }
+
+    /// Counts the entry against the entry count and unpacked size limits.
+    fn count(&mut self, header: &Header) -> Result<(), TarballDownloadError> {
+        self.file_count += 1;
+
+        if self.file_count > self.limits.max_file_count {
+            return Err(unsafe_archive(format!(
+                "archive contains more than {} entries",
+                self.limits.max_file_count
+            )));
+        }
+
+        if let EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse =
+            header.entry_type()
+        {
+            self.unpacked_size = self.unpacked_size.saturating_add(header.size()?);
+
+            if self.unpacked_size > self.limits.max_unpacked_size {
+                return Err(unsafe_archive(format!(
+                    "archive exceeds the maximum unpacked size of {} bytes",
+                    self.limits.max_unpacked_size
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `None` for entries which should be skipped.
+    fn validate<R: Read>(
+        &mut self,
+        entry: &Entry<R>,
+    ) -> Result<Option<ValidatedEntry>, TarballDownloadError> {
+        let entry_path = entry.path()?.into_owned();
+
+        let normalized_path = normalize_inside_root(&entry_path).ok_or_else(|| {
+            unsafe_archive(format!(
+                "entry {} points outside of the archive",
+                entry_path.display()
+            ))
+        })?;
+
+        self.count(entry.header())?;
+
+        let entry_type = entry.header().entry_type();
+
+        match entry_type {
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+                Ok(Some(ValidatedEntry::File(normalized_path)))
+            }
+            EntryType::Directory => Ok(Some(ValidatedEntry::Directory(normalized_path))),
+            EntryType::Symlink | EntryType::Link => {
+                let link_name = entry.link_name()?.ok_or_else(|| {
+                    unsafe_archive(format!("link {} has no target", entry_path.display()))
+                })?;
+
+                // symlinks are relative to the directory containing them,
+                // hard links are relative to the root of the archive
+                let link_target = if entry_type == EntryType::Symlink {
+                    normalized_path
+                        .parent()
+                        .unwrap_or_else(|| Path::new(""))
+                        .join(&link_name)
+                } else {
+                    link_name.into_owned()
+                };
+
+                let target = normalize_inside_root(&link_target).ok_or_else(|| {
+                    unsafe_archive(format!(
+                        "link {} points outside of the archive",
+                        entry_path.display()
+                    ))
+                })?;
+
+                Ok(Some(ValidatedEntry::Link {
+                    path: normalized_path,
+                    target,
+                }))
+            }
+            _ => {
+                debug!(
+                    "skipping entry {} of type {:?}",
+                    entry_path.display(),
+                    entry_type
+                );
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Unpacks an untrusted tar stream into `destination_dir` entry by entry,
+/// refusing entries and links that point outside of it and enforcing `limits`.
+pub fn unpack_safely<R: Read, P: AsRef<Path>>(
+    reader: R,
+    destination_dir: P,
+    limits: &ExtractionLimits,
+) -> Result<(), TarballDownloadError> {
+    let destination_dir = destination_dir.as_ref();
+    let mut archive = Archive::new(reader);
+    let mut validator = EntryValidator::new(limits);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        if validator.validate(&entry)?.is_none() {
+            continue;
+        }
+
+        if !entry.unpack_in(destination_dir)? {
+            return Err(unsafe_archive(format!(
+                "entry {} could not be unpacked safely",
+                entry.path()?.display()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies an uncompressed tar stream up to its end marker, failing as soon
+/// as its entries exceed `limits`. Extended headers don't count against the
+/// unpacked size, they are limited to `MAX_EXTENDED_HEADER_SIZE` each.
+pub fn copy_archive<R: Read, W: Write>(
+    reader: R,
+    writer: &mut W,
+    limits: &ExtractionLimits,
+) -> Result<(), TarballDownloadError> {
+    let mut archive = Archive::new(TeeReader { reader, writer });
+    let mut validator = EntryValidator::new(limits);
+
+    // raw entries include the extended headers instead of applying them
+    for entry in archive.entries()?.raw(true) {
+        let mut entry = entry?;
+        let header = entry.header();
+
+        match header.entry_type() {
+            EntryType::XHeader
+            | EntryType::XGlobalHeader
+            | EntryType::GNULongName
+            | EntryType::GNULongLink => {
+                if header.size()? > MAX_EXTENDED_HEADER_SIZE {
+                    return Err(unsafe_archive(format!(
+                        "extended header exceeds the maximum size of {} bytes",
+                        MAX_EXTENDED_HEADER_SIZE
+                    )));
+                }
+            }
+            _ => validator.count(header)?,
+        }
+
+        io::copy(&mut entry, &mut io::sink())?;
+    }
+
+    Ok(())
+}
+
+impl TarballIndex {
+    /// Indexes an uncompressed tar archive, applying the same
+    /// checks `unpack_safely` does. Links are resolved to the
+    /// files they point to, links to directories are skipped.
+    pub fn build<R: Read>(
+        reader: R,
+        limits: &ExtractionLimits,
+    ) -> Result<Self, TarballDownloadError> {
+        let mut archive = Archive::new(reader);
+        let mut validator = EntryValidator::new(limits);
+        let mut index = TarballIndex::default();
+        let mut links: Vec<(PathBuf, PathBuf)> = vec![];
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+
+            match validator.validate(&entry)? {
+                Some(ValidatedEntry::File(path)) => {
+                    index.add_parent_directories(&path);
+                    index.files.insert(
+                        path_to_key(&path),
+                        IndexEntry {
+                            offset: entry.raw_file_position(),
+                            size: entry.header().size()?,
+                        },
+                    );
+                }
+                Some(ValidatedEntry::Directory(path)) => {
+                    index.add_parent_directories(&path);
+                    index.directories.insert(path_to_key(&path));
+                }
+                Some(ValidatedEntry::Link { path, target }) => links.push((path, target)),
+                None => {}
+            }
+        }
+
+        for (path, target) in links {
+            if let Some(entry) = index.files.get(&path_to_key(&target)).copied() {
+                index.add_parent_directories(&path);
+                index.files.insert(path_to_key(&path), entry);
+            } else {
+                debug!("skipping link {} to {}", path.display(), target.display());
+            }
+        }
+
+        Ok(index)
+    }
+
+    fn add_parent_directories(&mut self, path: &Path) {
+        for ancestor in path.ancestors().skip(1) {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+
+            self.directories.insert(path_to_key(ancestor));
+        }
+    }
+
+    /// Rough estimate of the memory the index takes.
+    pub fn approximate_size(&self) -> usize {
+        let files: usize = self
+            .files
+            .keys()
+            .map(|key| key.len() + size_of::<String>() + size_of::<IndexEntry>())
+            .sum();
+
+        let directories: usize = self
+            .directories
+            .iter()
+            .map(|key| key.len() + size_of::<String>())
+            .sum();
+
+        size_of::<Self>() + files + directories
+    }
+
+    pub fn get_file(&self, path: &str) -> Option<IndexEntry> {
+        self.files.get(path).copied()
+    }
+
+    pub fn is_directory(&self, path: &str) -> bool {
+        self.directories.contains(path)
+    }
+
+    /// Lists direct children of a directory: their names
+    /// and, for files, their locations in the archive.
+    pub fn read_directory(&self, path: &str) -> Vec<(String, Option<IndexEntry>)> {
+        let prefix = format!("{}/", path);
+        let is_child =
+            |key: &&String| key.starts_with(&prefix) && !key[prefix.len()..].contains('/');
+
+        let directories = self
+            .directories
+            .iter()
+            .filter(is_child)
+            .map(|key| (key[prefix.len()..].to_string(), None));
+
+        let files = self
+            .files
+            .iter()
+            .filter(|(key, _)| is_child(key))
+            .map(|(key, entry)| (key[prefix.len()..].to_string(), Some(*entry)));
+
+        directories.chain(files).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, RngCore};
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all};
+    use tar::{Builder, Header};
+
+    const LIMITS: ExtractionLimits = ExtractionLimits {
+        max_unpacked_size: 1024,
+        max_file_count: 8,
+    };
+
+    fn create_destination() -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "npm-package-explorer-test-{}",
+            thread_rng().next_u64()
+        ));
+        create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn append_file(builder: &mut Builder<Vec<u8>>, path: &str, content: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_entry_type(EntryType::Regular);
+        builder.append_data(&mut header, path, content).unwrap();
+    }
+
+    fn append_link(
+        builder: &mut Builder<Vec<u8>>,
+        entry_type: EntryType,
+        path: &str,
+        target: &str,
+    ) {
+        let mut header = Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_entry_type(entry_type);
+        header.set_link_name(target).unwrap();
+        builder.append_data(&mut header, path, &[][..]).unwrap();
+    }
+
+    fn unpack(archive: Vec<u8>) -> (PathBuf, Result<(), TarballDownloadError>) {
+        let destination = create_destination();
+        let result = unpack_safely(&archive[..], &destination, &LIMITS);
+        (destination, result)
+    }
+
+    #[test]
+    fn test_unpacks_regular_archive() {
+        let mut builder = Builder::new(vec![]);
+        append_file(&mut builder, "package/README.md", b"# hello");
+        append_file(&mut builder, "package/lib/index.js", b"module.exports = 1;");
+        append_link(
+            &mut builder,
+            EntryType::Symlink,
+            "package/main.js",
+            "lib/index.js",
+        );
+
+        let (destination, result) = unpack(builder.into_inner().unwrap());
+
+        assert!(result.is_ok());
+        assert_eq!(
+            read_to_string(destination.join("package/README.md")).unwrap(),
+            "# hello"
+        );
+        assert_eq!(
+            read_to_string(destination.join("package/main.js")).unwrap(),
+            "module.exports = 1;"
+        );
+
+        remove_dir_all(destination).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_links_outside_of_destination() {
+        let links = [
+            (EntryType::Symlink, "package/passwd", "/etc/passwd"),
+            (EntryType::Symlink, "package/lib/up", "../../../outside"),
+            (EntryType::Link, "package/hard", "../outside"),
+            (EntryType::Link, "package/hard", "/etc/passwd"),
+        ];
+
+        for (entry_type, path, target) in links.iter() {
+            let mut builder = Builder::new(vec![]);
+            append_link(&mut builder, *entry_type, path, target);
+
+            let (destination, result) = unpack(builder.into_inner().unwrap());
+
+            assert!(
+                matches!(result, Err(TarballDownloadError::UnsafeArchive(_))),
+                "{} -> {} must be rejected",
+                path,
+                target
+            );
+
+            remove_dir_all(destination).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_enforces_unpacked_size_limit() {
+        let mut builder = Builder::new(vec![]);
+        append_file(&mut builder, "package/a.bin", &[0; 600]);
+        append_file(&mut builder, "package/b.bin", &[0; 600]);
+
+        let (destination, result) = unpack(builder.into_inner().unwrap());
+
+        assert!(matches!(
+            result,
+            Err(TarballDownloadError::UnsafeArchive(_))
+        ));
+
+        remove_dir_all(destination).unwrap();
+    }
+
+    #[test]
+    fn test_indexes_archive() {
+        let mut builder = Builder::new(vec![]);
+        append_file(&mut builder, "package/README.md", b"# hello");
+        append_file(&mut builder, "package/lib/index.js", b"module.exports = 1;");
+        append_link(
+            &mut builder,
+            EntryType::Symlink,
+            "package/main.js",
+            "lib/index.js",
+        );
+        append_link(&mut builder, EntryType::Symlink, "package/dist", "lib");
+        let archive = builder.into_inner().unwrap();
+
+        let index = TarballIndex::build(&archive[..], &LIMITS).unwrap();
+
+        let read = |path: &str| {
+            let entry = index.get_file(path).unwrap();
+            let start = entry.offset as usize;
+            String::from_utf8(archive[start..start + entry.size as usize].to_vec()).unwrap()
+        };
+
+        assert_eq!(read("package/README.md"), "# hello");
+        assert_eq!(read("package/main.js"), "module.exports = 1;");
+        assert!(index.is_directory("package"));
+        assert!(index.is_directory("package/lib"));
+        assert!(!index.is_directory("package/README.md"));
+        assert_eq!(index.get_file("package/lib"), None);
+        assert!(!index.is_directory("package/dist"));
+        assert_eq!(index.get_file("package/dist/index.js"), None);
+
+        let names: Vec<String> = index
+            .read_directory("package")
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+
+        assert_eq!(names, vec!["lib", "README.md", "main.js"]);
+    }
+
+    #[test]
+    fn test_index_enforces_limits() {
+        let mut builder = Builder::new(vec![]);
+        append_file(&mut builder, "package/a.bin", &[0; 600]);
+        append_file(&mut builder, "package/b.bin", &[0; 600]);
+        append_link(&mut builder, EntryType::Symlink, "package/up", "../../x");
+        let archive = builder.into_inner().unwrap();
+
+        assert!(matches!(
+            TarballIndex::build(&archive[..], &LIMITS),
+            Err(TarballDownloadError::UnsafeArchive(_))
+        ));
+
+        let mut builder = Builder::new(vec![]);
+        append_file(&mut builder, "package/large.bin", &[0; 64 * 1024]);
+        let archive = builder.into_inner().unwrap();
+
+        assert!(matches!(
+            copy_archive(&archive[..], &mut vec![], &LIMITS),
+            Err(TarballDownloadError::UnsafeArchive(_))
+        ));
+    }
+
+    #[test]
+    fn test_copies_archives_with_long_paths() {
+        let mut builder = Builder::new(vec![]);
+
+        // each path takes a GNU long name entry of 4 kB,
+        // way more than the whole unpacked size limit
+        for index in 0..LIMITS.max_file_count {
+            let path = format!("package/{}/{}.js", "a".repeat(4000), index);
+            append_file(&mut builder, &path, b"1;");
+        }
+
+        let archive = builder.into_inner().unwrap();
+        let mut copy = vec![];
+
+        copy_archive(&archive[..], &mut copy, &LIMITS).unwrap();
+
+        let index = TarballIndex::build(&copy[..], &LIMITS).unwrap();
+        let path = format!("package/{}/0.js", "a".repeat(4000));
+
+        assert_eq!(index.get_file(&path).unwrap().size, 2);
+
+        let mut builder = Builder::new(vec![]);
+        let path = format!("package/{}.js", "a".repeat(2 * 1024 * 1024));
+        append_file(&mut builder, &path, b"1;");
+        let archive = builder.into_inner().unwrap();
+
+        assert!(matches!(
+            copy_archive(&archive[..], &mut vec![], &LIMITS),
+            Err(TarballDownloadError::UnsafeArchive(_))
+        ));
+    }
+
+    #[test]
+    fn test_enforces_file_count_limit() {
+        let mut builder = Builder::new(vec![]);
+
+        for index in 0..10 {
+            append_file(&mut builder, &format!("package/{}.js", index), b"");
+        }
+
+        let (destination, result) = unpack(builder.into_inner().unwrap());
+
+        assert!(matches!(
+            result,
+            Err(TarballDownloadError::UnsafeArchive(_))
+        ));
+
+        remove_dir_all(destination).unwrap();
+    }
+}
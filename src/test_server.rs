@@ -0,0 +1,63 @@
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+/// An HTTP server for tests standing in for a registry.
+pub struct TestServer {
+    /// The base url with a trailing slash.
+    pub url: String,
+    requests: Arc<AtomicUsize>,
+}
+
+/// A raw HTTP response closing the connection.
+pub fn response(status: u16, headers: &[(&str, &str)], body: &str) -> String {
+    let mut response = format!(
+        "HTTP/1.1 {} Test\r\ncontent-length: {}\r\nconnection: close\r\n",
+        status,
+        body.len()
+    );
+
+    for (name, value) in headers.iter() {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    response.push_str("\r\n");
+    response.push_str(body);
+    response
+}
+
+impl TestServer {
+    /// Answers the n-th request, counting from 0, with `respond(n)`.
+    pub fn start<F>(respond: F) -> Self
+    where
+        F: Fn(usize) -> String + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0; 4096]);
+
+                let response = respond(counter.fetch_add(1, Ordering::SeqCst));
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+
+        TestServer { url, requests }
+    }
+
+    /// The number of requests received so far.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}